@@ -1,57 +1,63 @@
-use std::{ffi::CString, sync::mpsc::Receiver, thread, time::Duration};
+use std::{
+    fmt,
+    sync::mpsc::{Receiver, RecvTimeoutError, Sender},
+    time::{Duration, Instant},
+};
 
-use falcon_key_file::FalconKeyfile;
+use falcon_key_file::{Callback, FalconKeyfile, Modifier};
 
-use crate::msgpack::ProtocolMessage;
-use crate::{messages::Message, state::State};
+use crate::config::Mappings;
+use crate::enet_server::{self, Outgoing};
+use crate::msgpack::{CallbackAction, CallbackInfo, ErrorCode, ProtocolMessage};
+use crate::{
+    messages::Message,
+    state::{ClientId, State, lock},
+};
 
-use crate::keyboard_emulator;
-use log::{debug, error, info};
+use crate::input_backend::{InputBackend, InputError};
+use crate::key_strokes::{HeldModifiers, KeyBinding};
+use log::{debug, error, info, warn};
 
 use std::collections::HashMap;
 
+/// Keys held down longer than this are released, in case the release got lost.
+const MAX_KEY_HOLD: Duration = Duration::from_secs(10);
+
 pub struct CallbackSender {
     rx: Receiver<Message>,
+    packet_tx: Sender<Outgoing>,
+    input: Box<dyn InputBackend>,
     state: State,
     key_file: Option<FalconKeyfile>,
-    icp_to_callback_names: HashMap<String, String>,
+    mappings: Mappings,
+    /// Keys currently held down, per peer and control.
+    held_keys: HashMap<ClientId, HashMap<String, HeldKey>>,
+    /// The modifiers of all held keys, across peers.
+    held_modifiers: HeldModifiers,
 }
 
-impl CallbackSender {
-    pub fn new(rx: Receiver<Message>, state: State) -> Self {
-        let icp_to_callback_names = [
-            ("1".to_string(), "SimICPTILS".to_string()),
-            ("2".to_string(), "SimICPALOW".to_string()),
-            ("3".to_string(), "SimICPTHREE".to_string()),
-            ("4".to_string(), "SimICPStpt".to_string()),
-            ("5".to_string(), "SimICPCrus".to_string()),
-            ("6".to_string(), "SimICPSIX".to_string()),
-            ("7".to_string(), "SimICPMark".to_string()),
-            ("8".to_string(), "SimICPEIGHT".to_string()),
-            ("9".to_string(), "SimICPNINE".to_string()),
-            ("0".to_string(), "SimICPZERO".to_string()),
-            ("RCL".to_string(), "SimICPCLEAR".to_string()),
-            ("ENTER".to_string(), "SimICPEnter".to_string()),
-            ("COM1".to_string(), "SimICPCom1".to_string()),
-            ("COM2".to_string(), "SimICPCom2".to_string()),
-            ("IFF".to_string(), "SimICPIFF".to_string()),
-            ("LIST".to_string(), "SimICPLIST".to_string()),
-            ("A-A".to_string(), "SimICPAA".to_string()),
-            ("A-G".to_string(), "SimICPAG".to_string()),
-            ("icp-wpt-next".to_string(), "SimICPNext".to_string()),
-            ("icp-wpt-previous".to_string(), "SimICPPrevious".to_string()),
-            ("icp-ded-up".to_string(), "SimICPDEDUP".to_string()),
-            ("icp-ded-down".to_string(), "SimICPDEDDOWN".to_string()),
-            ("icp-ded-seq".to_string(), "SimICPDEDSEQ".to_string()),
-            ("icp-ded-return".to_string(), "SimICPResetDED".to_string()),
-        ]
-        .into();
+struct HeldKey {
+    binding: KeyBinding,
+    pressed_at: Instant,
+}
 
+impl CallbackSender {
+    pub fn new(
+        rx: Receiver<Message>,
+        packet_tx: Sender<Outgoing>,
+        input: Box<dyn InputBackend>,
+        mappings: Mappings,
+        state: State,
+    ) -> Self {
         Self {
             rx,
+            packet_tx,
+            input,
             state,
             key_file: None,
-            icp_to_callback_names,
+            mappings,
+            held_keys: HashMap::new(),
+            held_modifiers: HeldModifiers::default(),
         }
     }
 
@@ -64,76 +70,545 @@ impl CallbackSender {
             {
                 break;
             }
-            let message = self.rx.recv();
-            if let Ok(message) = message {
-                match message {
-                    Message::EnetReceived { message } => self.handle_message_received(message),
-                    Message::KeyfileRead { key_file } => {
-                        self.key_file.replace(key_file);
-                    }
-                }
+            match self.rx.recv_timeout(Duration::from_millis(100)) {
+                Ok(message) => self.handle(message),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
             }
+            self.release_expired();
+        }
+
+        // shutting down, make sure nothing stays held down in BMS.
+        let peers: Vec<ClientId> = self.held_keys.keys().copied().collect();
+        for peer_id in peers {
+            self.release_all(peer_id);
         }
     }
 
-    fn handle_message_received(&self, message: ProtocolMessage) {
+    fn handle(&mut self, message: Message) {
         match message {
-            ProtocolMessage::IcpButtonPressed { icp: _, button } => {
-                if let Some(callback_name) = self.icp_to_callback_names.get(&button) {
-                    self.invoke_callback(callback_name.clone());
-                }
+            Message::EnetReceived { peer_id, message } => {
+                self.handle_message_received(peer_id, message)
+            }
+            Message::PeerDisconnected { peer_id } => self.release_all(peer_id),
+            Message::KeyfileRead { key_file } => {
+                self.key_file.replace(key_file);
+                self.push_callbacks();
+            }
+            Message::MappingsChanged { mappings } => self.mappings = mappings,
+        }
+    }
+
+    fn handle_message_received(&mut self, peer_id: ClientId, message: ProtocolMessage) {
+        let (correlation_id, result) = match message {
+            ProtocolMessage::IcpButtonPressed {
+                icp: _,
+                button,
+                correlation_id,
+            } => {
+                let result = match self.mappings.icp.get(&button) {
+                    Some(callback_name) => {
+                        self.press(peer_id, format!("icp/{}", button), callback_name.clone())
+                    }
+                    None => Err(CallbackError::UnknownButton(button)),
+                };
+                (correlation_id, result)
             }
-            ProtocolMessage::OsbButtonPressed { mfd, osb } => {
-                let mfd_suffix = match mfd.as_str() {
-                    "f16/left-mfd" => Some("L"),
-                    "f16/right-mfd" => Some("R"),
-                    any => {
-                        error!("Received unknown mfd identifier: {}", any);
-                        None
+            ProtocolMessage::OsbButtonPressed {
+                mfd,
+                osb,
+                correlation_id,
+            } => {
+                let result = match self.mappings.osb.get(&mfd) {
+                    Some(pattern) => {
+                        let callback_name = pattern.replace("{osb}", &osb);
+                        self.press(peer_id, format!("{}/{}", mfd, osb), callback_name)
                     }
+                    None => Err(CallbackError::UnknownMfd(mfd)),
+                };
+                (correlation_id, result)
+            }
+            ProtocolMessage::IcpButtonReleased {
+                icp: _,
+                button,
+                correlation_id,
+            } => (
+                correlation_id,
+                self.release(peer_id, &format!("icp/{}", button)),
+            ),
+            ProtocolMessage::OsbButtonReleased {
+                mfd,
+                osb,
+                correlation_id,
+            } => (
+                correlation_id,
+                self.release(peer_id, &format!("{}/{}", mfd, osb)),
+            ),
+            ProtocolMessage::ListCallbacks { correlation_id } => {
+                let message = ProtocolMessage::Callbacks {
+                    correlation_id,
+                    callbacks: self.bound_callbacks(),
+                };
+                enet_server::send_message(&self.packet_tx, peer_id, &message);
+                return;
+            }
+            ProtocolMessage::InvokeCallback {
+                callback,
+                action,
+                correlation_id,
+            } => {
+                let control = format!("callback/{}", callback);
+                let result = match action.unwrap_or_default() {
+                    CallbackAction::Click => self.click(callback),
+                    CallbackAction::Press => self.press(peer_id, control, callback),
+                    CallbackAction::Release => self.release(peer_id, &control),
                 };
-                if let Some(mfd_suffix) = mfd_suffix {
-                    let callback_name = format!("SimCBE{}{}", osb, mfd_suffix);
-                    self.invoke_callback(callback_name);
+                (correlation_id, result)
+            }
+            any => {
+                debug!(
+                    "Received a msgpack message that was not supposed to invoke a callback: {:?}",
+                    any
+                );
+                return;
+            }
+        };
+
+        self.reply(peer_id, correlation_id, result);
+    }
+
+    /// Tells the peer about failures, and about successes if it asked for them with a correlation id.
+    fn reply(
+        &self,
+        peer_id: ClientId,
+        correlation_id: Option<u32>,
+        result: Result<(), CallbackError>,
+    ) {
+        let message = match result {
+            Ok(()) if correlation_id.is_some() => ProtocolMessage::Acknowledged { correlation_id },
+            Ok(()) => return,
+            Err(e) => {
+                error!("{}", e);
+                let code = e.code();
+                let message = e.to_string();
+                let suggestions = match e {
+                    CallbackError::UnknownCallback { suggestions, .. } => suggestions,
+                    _ => vec![],
+                };
+                ProtocolMessage::Error {
+                    correlation_id,
+                    code,
+                    message,
+                    suggestions,
                 }
             }
-            ProtocolMessage::IcpButtonReleased { icp: _, button: _ } => {
-                // intentionally left blank
+        };
+        enet_server::send_message(&self.packet_tx, peer_id, &message);
+    }
+
+    /// All callbacks in the key file that are bound to a key.
+    fn bound_callbacks(&self) -> Vec<CallbackInfo> {
+        let Some(ref kf) = self.key_file else {
+            return vec![];
+        };
+        kf.callbacks()
+            .filter(|callback| callback.key_code != 0)
+            .map(callback_info)
+            .collect()
+    }
+
+    /// Tells all connected peers about the callbacks of a newly read key file.
+    fn push_callbacks(&self) {
+        let message = ProtocolMessage::Callbacks {
+            correlation_id: None,
+            callbacks: self.bound_callbacks(),
+        };
+        let peers = lock(&self.state.connected_peers).clone();
+        for peer_id in peers {
+            enet_server::send_message(&self.packet_tx, peer_id, &message);
+        }
+    }
+
+    /// Presses and releases the keys of `callback` right away.
+    fn click(&mut self, callback: String) -> Result<(), CallbackError> {
+        let binding = self.key_binding(callback)?;
+        let press = self.held_modifiers.press(&binding);
+        let release = self.held_modifiers.release(&binding);
+
+        self.input.focus_target()?;
+        self.input.send(&press)?;
+        self.input.send(&release)?;
+        Ok(())
+    }
+
+    /// Presses the keys of `callback` and keeps them held down until `control` is released.
+    fn press(
+        &mut self,
+        peer_id: ClientId,
+        control: String,
+        callback: String,
+    ) -> Result<(), CallbackError> {
+        let binding = self.key_binding(callback)?;
+
+        // pressed again without a release in between, let go of it first.
+        self.release(peer_id, &control)?;
+
+        self.input.focus_target()?;
+        let press = self.held_modifiers.press(&binding);
+        // held from here on, so it gets released even if sending fails halfway.
+        self.held_keys.entry(peer_id).or_default().insert(
+            control,
+            HeldKey {
+                binding,
+                pressed_at: Instant::now(),
+            },
+        );
+        self.input.send(&press)?;
+        Ok(())
+    }
+
+    fn release(&mut self, peer_id: ClientId, control: &str) -> Result<(), CallbackError> {
+        let held_key = self
+            .held_keys
+            .get_mut(&peer_id)
+            .and_then(|keys| keys.remove(control));
+        if let Some(held_key) = held_key {
+            let release = self.held_modifiers.release(&held_key.binding);
+            self.input.send(&release)?;
+        }
+        Ok(())
+    }
+
+    /// Lets go of everything the peer is holding down, so nothing stays stuck when it goes away.
+    fn release_all(&mut self, peer_id: ClientId) {
+        let Some(keys) = self.held_keys.remove(&peer_id) else {
+            return;
+        };
+        for (control, held_key) in keys {
+            debug!("Releasing {} held by {:?}", control, peer_id);
+            let release = self.held_modifiers.release(&held_key.binding);
+            if let Err(e) = self.input.send(&release) {
+                error!("Failed to release {}: {:?}", control, e);
+            }
+        }
+    }
+
+    /// Releases keys that have been held for too long, the release probably got lost.
+    fn release_expired(&mut self) {
+        let expired: Vec<(ClientId, String)> = self
+            .held_keys
+            .iter()
+            .flat_map(|(peer_id, keys)| {
+                keys.iter()
+                    .filter(|(_, held_key)| held_key.pressed_at.elapsed() > MAX_KEY_HOLD)
+                    .map(|(control, _)| (*peer_id, control.clone()))
+            })
+            .collect();
+
+        for (peer_id, control) in expired {
+            warn!("Releasing {} held by {:?} for too long", control, peer_id);
+            if let Err(e) = self.release(peer_id, &control) {
+                error!("{}", e);
+            }
+        }
+    }
+
+    /// The keys `callback` is bound to.
+    fn key_binding(&self, callback: String) -> Result<KeyBinding, CallbackError> {
+        let Some(ref kf) = self.key_file else {
+            return Err(CallbackError::NoKeyFile);
+        };
+
+        if let Some(callback) = kf.callback(&callback) {
+            info!("Received {:?}", callback);
+            if callback.key_code == 0 {
+                return Err(CallbackError::NotBound(callback.name.clone()));
+            }
+            Ok(KeyBinding::from(&callback))
+        } else {
+            let suggestions = kf
+                .propose_callback_names(callback.clone(), 3)
+                .into_iter()
+                .map(|name| name.to_string())
+                .collect();
+            Err(CallbackError::UnknownCallback {
+                name: callback,
+                suggestions,
+            })
+        }
+    }
+}
+
+fn callback_info(callback: &Callback) -> CallbackInfo {
+    let readable = |modifiers: &[Modifier]| modifiers.iter().map(|m| format!("{:?}", m)).collect();
+    CallbackInfo {
+        name: callback.name.clone(),
+        key: format!("{:?}", callback.readable_key_code),
+        modifiers: readable(&callback.modifiers),
+        combo_key: (callback.combo_key_code != 0)
+            .then(|| format!("{:?}", callback.readable_combo_key_code)),
+        combo_modifiers: readable(&callback.combo_modifiers),
+    }
+}
+
+/// Everything that can go wrong between receiving a button press and BMS seeing a key.
+#[derive(Debug)]
+pub enum CallbackError {
+    NoKeyFile,
+    UnknownButton(String),
+    UnknownMfd(String),
+    UnknownCallback {
+        name: String,
+        suggestions: Vec<String>,
+    },
+    /// In the key file, but without a key to press for it.
+    NotBound(String),
+    BmsWindowNotFound,
+    Input(InputError),
+}
+
+impl From<InputError> for CallbackError {
+    fn from(e: InputError) -> Self {
+        match e {
+            InputError::TargetNotFound => CallbackError::BmsWindowNotFound,
+            e => CallbackError::Input(e),
+        }
+    }
+}
+
+impl CallbackError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            CallbackError::NoKeyFile => ErrorCode::KeyFileNotLoaded,
+            CallbackError::UnknownButton(_) | CallbackError::UnknownMfd(_) => {
+                ErrorCode::UnknownIdentifier
             }
-            ProtocolMessage::OsbButtonReleased { mfd: _, osb: _ } => {
-                // intentionally left blank
+            CallbackError::UnknownCallback { .. } => ErrorCode::UnknownCallback,
+            CallbackError::NotBound(_) => ErrorCode::CallbackNotBound,
+            CallbackError::BmsWindowNotFound => ErrorCode::BmsNotRunning,
+            CallbackError::Input(_) => ErrorCode::InputFailed,
+        }
+    }
+}
+
+impl fmt::Display for CallbackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallbackError::NoKeyFile => write!(f, "No key file has been read from BMS yet"),
+            CallbackError::UnknownButton(button) => {
+                write!(f, "Received unknown icp button '{}'", button)
             }
-            any => debug!(
-                "Received a msgpack message that was not supposed to invoke a callback: {:?}",
-                any
+            CallbackError::UnknownMfd(mfd) => write!(f, "Received unknown mfd identifier: {}", mfd),
+            CallbackError::UnknownCallback { name, suggestions } => write!(
+                f,
+                "Callback '{}' is not in the key file. Did you mean {:?}?",
+                name, suggestions
             ),
+            CallbackError::NotBound(name) => write!(f, "Callback {} not bound", name),
+            CallbackError::BmsWindowNotFound => write!(f, "Have not found BMS window!"),
+            CallbackError::Input(e) => write!(f, "Failed to send keys to BMS: {:?}", e),
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        fs::{self, File},
+        sync::{
+            Arc, Mutex,
+            atomic::AtomicBool,
+            mpsc::{self, Receiver},
+        },
+    };
+
+    use super::*;
+    use crate::config::Config;
+    use crate::input_backend::{RecordedStrokes, RecordingInputBackend};
+    use crate::key_strokes::{Direction, KeyStroke, ScanCode};
+    use crate::state::InnerState;
+
+    const LSHIFT: ScanCode = 0x2A;
+    const KEY_F1: ScanCode = 0x3B;
+    const KEY_1: ScanCode = 0x02;
+
+    /// SimICPNext on F1, SimCBE1L on shift 1, and SimCBE2L without a key.
+    const KEY_FILE: &str = "\
+SimICPNext -1 0 0X3B 0 0 0 1 \"ICP: Next\"
+SimCBE1L -1 0 0X2 1 0 0 1 \"Left MFD: OSB 1\"
+SimCBE2L -1 0 0XFFFFFFFF 0 0 0 1 \"Left MFD: OSB 2\"
+";
+
+    struct TestSender {
+        sender: CallbackSender,
+        strokes: RecordedStrokes,
+        packets: Receiver<Outgoing>,
+    }
 
-    fn invoke_callback(&self, callback: String) {
-        if let Some(ref kf) = self.key_file {
-            if let Some(callback) = kf.callback(&callback) {
-                info!("Received {:?}", callback);
+    impl TestSender {
+        fn new() -> Self {
+            let (_tx, rx) = mpsc::channel();
+            let (packet_tx, packets) = mpsc::channel();
+            let strokes: RecordedStrokes = Arc::new(Mutex::new(VecDeque::new()));
+            let state = State::new(InnerState::new(
+                Arc::new(AtomicBool::new(false)),
+                Config::default().streams,
+            ));
+            let mut sender = CallbackSender::new(
+                rx,
+                packet_tx,
+                Box::new(RecordingInputBackend::new(Arc::clone(&strokes))),
+                Config::default().mappings,
+                state,
+            );
+            sender.handle(Message::KeyfileRead {
+                key_file: parse_key_file(),
+            });
+            Self {
+                sender,
+                strokes,
+                packets,
+            }
+        }
 
-                let window_name = CString::new("Falcon BMS").unwrap();
+        fn receive(&mut self, peer_id: u64, message: ProtocolMessage) {
+            self.sender.handle(Message::EnetReceived {
+                peer_id: ClientId(peer_id),
+                message,
+            });
+        }
 
-                unsafe {
-                    let window_handle =
-                        user32::FindWindowA(std::ptr::null_mut(), window_name.as_ptr());
-                    // probably SetForegroundWindow is enough, it was in the other server code.
-                    if window_handle.is_null() {
-                        error!("Have not found BMS window!");
-                        return;
-                    }
-                    user32::SetForegroundWindow(window_handle);
-                    user32::ShowWindow(window_handle, 9);
-                }
-                thread::sleep(Duration::from_millis(15));
-                keyboard_emulator::invoke(callback);
-            } else {
-                error!("Received unknown callback '{}'", callback);
-                error!("Did you mean {:?}?", kf.propose_callback_names(callback, 3));
+        /// The key strokes sent since the last call.
+        fn strokes(&self) -> Vec<KeyStroke> {
+            lock(&self.strokes).drain(..).collect()
+        }
+
+        fn reply(&self) -> ProtocolMessage {
+            match self.packets.try_recv().expect("Nothing was sent") {
+                Outgoing::Packet(packet) => rmp_serde::from_slice(&packet.data).unwrap(),
+                outgoing => panic!("Expected a packet, got {:?}", outgoing),
             }
         }
     }
+
+    fn parse_key_file() -> FalconKeyfile {
+        let path = std::env::temp_dir().join(format!(
+            "falcon-bms-control-server-{}.key",
+            std::process::id()
+        ));
+        fs::write(&path, KEY_FILE).unwrap();
+        let key_file = falcon_key_file::parse("test.key".to_string(), &File::open(&path).unwrap())
+            .expect("Could not parse the key file");
+        let _ = fs::remove_file(path);
+        key_file
+    }
+
+    fn icp_pressed(button: &str) -> ProtocolMessage {
+        ProtocolMessage::IcpButtonPressed {
+            icp: None,
+            button: button.to_string(),
+            correlation_id: None,
+        }
+    }
+
+    fn osb_pressed(osb: &str, correlation_id: Option<u32>) -> ProtocolMessage {
+        ProtocolMessage::OsbButtonPressed {
+            mfd: "f16/left-mfd".to_string(),
+            osb: osb.to_string(),
+            correlation_id,
+        }
+    }
+
+    fn down(scan: ScanCode) -> KeyStroke {
+        KeyStroke::Key {
+            scan,
+            direction: Direction::Press,
+            extended: false,
+        }
+    }
+
+    fn up(scan: ScanCode) -> KeyStroke {
+        KeyStroke::Key {
+            scan,
+            direction: Direction::Release,
+            extended: false,
+        }
+    }
+
+    fn pause() -> KeyStroke {
+        KeyStroke::Pause(Duration::from_millis(10))
+    }
+
+    #[test]
+    fn holds_the_keys_of_pressed_buttons() {
+        let mut sender = TestSender::new();
+
+        sender.receive(1, icp_pressed("icp-wpt-next"));
+        assert_eq!(sender.strokes(), vec![pause(), down(KEY_F1)]);
+
+        sender.receive(1, osb_pressed("1", Some(7)));
+        assert_eq!(sender.strokes(), vec![down(LSHIFT), pause(), down(KEY_1)]);
+        assert!(matches!(
+            sender.reply(),
+            ProtocolMessage::Acknowledged {
+                correlation_id: Some(7)
+            }
+        ));
+
+        sender.receive(
+            1,
+            ProtocolMessage::OsbButtonReleased {
+                mfd: "f16/left-mfd".to_string(),
+                osb: "1".to_string(),
+                correlation_id: None,
+            },
+        );
+        assert_eq!(sender.strokes(), vec![up(KEY_1), up(LSHIFT)]);
+    }
+
+    #[test]
+    fn tells_the_client_about_buttons_it_cannot_press() {
+        let mut sender = TestSender::new();
+
+        sender.receive(1, osb_pressed("2", None));
+        assert!(matches!(
+            sender.reply(),
+            ProtocolMessage::Error {
+                code: ErrorCode::CallbackNotBound,
+                ..
+            }
+        ));
+        sender.receive(1, icp_pressed("icp-nope"));
+        assert!(matches!(
+            sender.reply(),
+            ProtocolMessage::Error {
+                code: ErrorCode::UnknownIdentifier,
+                ..
+            }
+        ));
+        assert!(sender.strokes().is_empty());
+    }
+
+    #[test]
+    fn releases_what_a_peer_held_when_it_disconnects() {
+        let mut sender = TestSender::new();
+        sender.receive(1, icp_pressed("icp-wpt-next"));
+        sender.receive(1, osb_pressed("1", None));
+        sender.receive(2, osb_pressed("1", None));
+        sender.strokes();
+
+        sender.sender.handle(Message::PeerDisconnected {
+            peer_id: ClientId(1),
+        });
+        // in no particular order, and the other peer still holds shift.
+        let strokes = sender.strokes();
+        assert_eq!(strokes.len(), 2);
+        assert!(strokes.contains(&up(KEY_1)) && strokes.contains(&up(KEY_F1)));
+
+        sender.sender.handle(Message::PeerDisconnected {
+            peer_id: ClientId(2),
+        });
+        assert_eq!(sender.strokes(), vec![up(KEY_1), up(LSHIFT)]);
+    }
 }
@@ -0,0 +1,84 @@
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use figment::{
+    Figment,
+    providers::{Env, Format, Serialized, Toml},
+};
+use serde::Serialize;
+
+use crate::VERSION;
+use crate::config::Config;
+
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+/// Environment variables starting with this override config values,
+/// nested sections are separated by `__`, e.g. `FBCS_TEXTURES__SOURCE`.
+const ENV_PREFIX: &str = "FBCS_";
+
+/// The server component of the falcon-bms-control app.
+#[derive(Debug, Clone, Parser)]
+#[command(version = VERSION)]
+pub struct Cli {
+    /// The config file to read [default: config.toml, if there is one]
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Print the effective configuration and exit.
+    #[arg(long)]
+    pub print_config: bool,
+
+    #[command(flatten)]
+    pub overrides: ConfigOverrides,
+}
+
+/// Config values given on the command line, these win over the config file
+/// and the environment.
+#[derive(Debug, Clone, clap::Args, Serialize)]
+pub struct ConfigOverrides {
+    /// The address to listen for connections and broadcast packets on.
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listen_address: Option<String>,
+
+    /// The port clients connect to.
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listen_port: Option<u16>,
+
+    /// The port clients look for servers on.
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub broadcast_port: Option<u16>,
+
+    /// One of off, error, warn, info, debug or trace.
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<String>,
+}
+
+impl Cli {
+    /// The config file that is read, whether it exists or not.
+    pub fn config_file(&self) -> &Path {
+        self.config
+            .as_deref()
+            .unwrap_or(Path::new(DEFAULT_CONFIG_FILE))
+    }
+
+    /// All config sources, in increasing order of precedence.
+    pub fn figment(&self) -> Figment {
+        // a config file given explicitly has to exist, the default one is optional.
+        let config_file = match self.config {
+            Some(ref path) => Toml::file_exact(path),
+            None => Toml::file(DEFAULT_CONFIG_FILE),
+        };
+
+        Figment::new()
+            .merge(Serialized::defaults(Config::default()))
+            .merge(config_file)
+            // kept for compatibility, this was the only variable read before.
+            .merge(Env::raw().only(&["LOG_LEVEL"]))
+            .merge(Env::prefixed(ENV_PREFIX).split("__"))
+            .merge(Serialized::defaults(&self.overrides))
+    }
+}
@@ -1,20 +1,334 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::{env, fs};
+
+use figment::Figment;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::error;
+
+/// The refresh rates and qualities allowed, for the defaults as well as in stream requests.
+pub(crate) const REFRESH_RATES: RangeInclusive<u16> = 1..=60;
+pub(crate) const QUALITIES: RangeInclusive<u16> = 1..=100;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub(crate) struct Config {
+    /// The name clients list the server by, defaults to the host name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_name: Option<String>,
     pub log_level: String,
     pub listen_address: String,
     pub listen_port: u16,
     pub broadcast_port: u16,
+    pub textures: TextureConfig,
+    pub input: InputConfig,
+    pub mappings: Mappings,
+    pub streams: StreamDefaults,
+    pub mdns: MdnsConfig,
+}
+
+/// Which key file callback the controls on the client invoke.
+/// Entries in `config.toml` are merged into the built-in defaults.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Mappings {
+    /// ICP button identifier to callback name.
+    pub icp: BTreeMap<String, String>,
+    /// MFD identifier to callback name, with `{osb}` replaced by the OSB number.
+    pub osb: BTreeMap<String, String>,
+}
+
+/// What texture streams use when the client does not ask for something else.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct StreamDefaults {
+    pub refresh_rate: u16,
+    pub quality: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct MdnsConfig {
+    /// Whether to advertise the server via multicast DNS.
+    pub enabled: bool,
+    /// Also advertise on the loopback interface, for browsing on this machine.
+    pub loopback: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct TextureConfig {
+    /// Where to read streamed textures from.
+    pub source: TextureSourceKind,
+    /// The directory images are served from when `source` is `files`.
+    pub directory: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TextureSourceKind {
+    /// BMS shared memory, the real thing.
+    Bms,
+    /// Still images or image sequences from a directory, for working without BMS.
+    Files,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct InputConfig {
+    /// How key strokes are delivered to BMS.
+    pub backend: InputBackendKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum InputBackendKind {
+    /// Win32 `SendInput` to the BMS window.
+    SendInput,
+    /// Only log and record the key strokes, for working without BMS.
+    Recording,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
+            server_name: None,
             log_level: "info".to_string(),
             listen_address: "0.0.0.0".to_string(),
             listen_port: 9022,
             broadcast_port: 9020,
+            textures: TextureConfig {
+                source: TextureSourceKind::Bms,
+                directory: "images".to_string(),
+            },
+            input: InputConfig {
+                backend: InputBackendKind::SendInput,
+            },
+            mappings: Mappings::default(),
+            streams: StreamDefaults {
+                refresh_rate: 30,
+                quality: 65,
+            },
+            mdns: MdnsConfig {
+                enabled: true,
+                loopback: false,
+            },
+        }
+    }
+}
+
+impl Config {
+    /// The configured server name, or else the name of this computer.
+    pub fn server_name(&self) -> String {
+        self.server_name
+            .clone()
+            .or_else(|| env::var("COMPUTERNAME").ok())
+            .or_else(|| env::var("HOSTNAME").ok())
+            .or_else(|| fs::read_to_string("/etc/hostname").ok())
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| "falcon-bms-control-server".to_string())
+    }
+
+    /// Extracts the config from all sources and checks every value,
+    /// so that all problems can be reported at once.
+    pub fn load(figment: &Figment) -> error::Result<Self> {
+        let config: Config = figment.extract()?;
+        let errors = config
+            .problems()
+            .into_iter()
+            .map(|(key, message)| {
+                let mut error = figment::Error::from(message).with_path(&key);
+                error.profile = Some(figment.profile().clone());
+                error.metadata = figment.find_metadata(&key).cloned();
+                error
+            })
+            // chaining puts the last error first, keep them in the order they were found.
+            .rev()
+            .reduce(|errors, error| errors.chain(error));
+        match errors {
+            Some(errors) => Err(errors.into()),
+            None => Ok(config),
+        }
+    }
+
+    /// Every invalid value, by its key.
+    fn problems(&self) -> Vec<(String, String)> {
+        let mut problems = vec![];
+        let mut report = |key: &str, message: String| problems.push((key.to_string(), message));
+
+        match self.listen_address.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) if !ip.is_unspecified() => report(
+                "listen_address",
+                format!(
+                    "connections are only accepted via IPv4, use '::' to listen on all IPv4 and IPv6 addresses instead of '{}'",
+                    ip
+                ),
+            ),
+            Ok(_) => {}
+            Err(_) => report(
+                "listen_address",
+                format!("'{}' is not an IP address", self.listen_address),
+            ),
+        }
+        if self.listen_port == 0 {
+            report("listen_port", "port 0 is not allowed".to_string());
+        }
+        if self.broadcast_port == 0 {
+            report("broadcast_port", "port 0 is not allowed".to_string());
+        }
+        if self.listen_port != 0 && self.listen_port == self.broadcast_port {
+            report(
+                "broadcast_port",
+                format!("port {} is already used for listen_port", self.listen_port),
+            );
+        }
+        // the same parser the logger uses, anything it accepts is a valid filter.
+        if let Err(e) = env_filter::Builder::new().try_parse(&self.log_level) {
+            report("log_level", e.to_string());
+        }
+        if let Err(problem) = check_range(self.streams.refresh_rate, &REFRESH_RATES) {
+            report("streams.refresh_rate", problem);
+        }
+        if let Err(problem) = check_range(self.streams.quality, &QUALITIES) {
+            report("streams.quality", problem);
+        }
+        for (mfd, pattern) in &self.mappings.osb {
+            if !pattern.contains("{osb}") {
+                report(
+                    &format!("mappings.osb.{}", mfd),
+                    format!("'{}' does not contain {{osb}}", pattern),
+                );
+            }
         }
+        if self.textures.source == TextureSourceKind::Files
+            && !Path::new(&self.textures.directory).is_dir()
+        {
+            report(
+                "textures.directory",
+                format!("'{}' is not a directory", self.textures.directory),
+            );
+        }
+
+        problems
+    }
+
+    /// The settings that differ from `running` but are only read at startup.
+    pub fn changes_needing_restart(&self, running: &Config) -> Vec<&'static str> {
+        let mut changes = vec![];
+        if self.server_name != running.server_name {
+            changes.push("server_name");
+        }
+        if self.listen_address != running.listen_address {
+            changes.push("listen_address");
+        }
+        if self.listen_port != running.listen_port {
+            changes.push("listen_port");
+        }
+        if self.broadcast_port != running.broadcast_port {
+            changes.push("broadcast_port");
+        }
+        if self.textures != running.textures {
+            changes.push("textures");
+        }
+        if self.input != running.input {
+            changes.push("input");
+        }
+        if self.mdns != running.mdns {
+            changes.push("mdns");
+        }
+        changes
+    }
+}
+
+impl Default for Mappings {
+    fn default() -> Self {
+        let icp = [
+            ("1", "SimICPTILS"),
+            ("2", "SimICPALOW"),
+            ("3", "SimICPTHREE"),
+            ("4", "SimICPStpt"),
+            ("5", "SimICPCrus"),
+            ("6", "SimICPSIX"),
+            ("7", "SimICPMark"),
+            ("8", "SimICPEIGHT"),
+            ("9", "SimICPNINE"),
+            ("0", "SimICPZERO"),
+            ("RCL", "SimICPCLEAR"),
+            ("ENTER", "SimICPEnter"),
+            ("COM1", "SimICPCom1"),
+            ("COM2", "SimICPCom2"),
+            ("IFF", "SimICPIFF"),
+            ("LIST", "SimICPLIST"),
+            ("A-A", "SimICPAA"),
+            ("A-G", "SimICPAG"),
+            ("icp-wpt-next", "SimICPNext"),
+            ("icp-wpt-previous", "SimICPPrevious"),
+            ("icp-ded-up", "SimICPDEDUP"),
+            ("icp-ded-down", "SimICPDEDDOWN"),
+            ("icp-ded-seq", "SimICPDEDSEQ"),
+            ("icp-ded-return", "SimICPResetDED"),
+        ];
+        let osb = [
+            ("f16/left-mfd", "SimCBE{osb}L"),
+            ("f16/right-mfd", "SimCBE{osb}R"),
+        ];
+
+        Self {
+            icp: to_map(&icp),
+            osb: to_map(&osb),
+        }
+    }
+}
+
+/// Returns `value` if it is within `range`, or else what is wrong with it.
+pub(crate) fn check_range(value: u16, range: &RangeInclusive<u16>) -> Result<u16, String> {
+    if range.contains(&value) {
+        Ok(value)
+    } else {
+        Err(format!(
+            "{} is out of range, expected {} to {}",
+            value,
+            range.start(),
+            range.end()
+        ))
+    }
+}
+
+fn to_map(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+    entries
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_level_problems(log_level: &str) -> Vec<(String, String)> {
+        Config {
+            log_level: log_level.to_string(),
+            ..Config::default()
+        }
+        .problems()
+        .into_iter()
+        .filter(|(key, _)| key == "log_level")
+        .collect()
+    }
+
+    #[test]
+    fn accepts_everything_env_logger_does() {
+        for log_level in [
+            "info",
+            "my_crate",
+            "warn,my_crate::module=trace",
+            "debug/foo.*",
+        ] {
+            assert_eq!(log_level_problems(log_level), vec![], "{}", log_level);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_levels() {
+        assert_eq!(log_level_problems("info,my_crate=verbose").len(), 1);
+        assert_eq!(log_level_problems("info/a/b").len(), 1);
     }
 }
@@ -0,0 +1,104 @@
+use std::{fs, sync::mpsc::Sender, time::Duration};
+
+use log::{debug, error, info, warn};
+
+use crate::cli::Cli;
+use crate::config::Config;
+use crate::logging;
+use crate::messages::Message;
+use crate::state::{State, lock};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Re-reads the config when the config file changes and applies what can be changed
+/// without a restart: the log level, the button mappings and the stream defaults.
+pub struct ConfigWatcher {
+    cli: Cli,
+    /// The config in effect, settings that need a restart keep their startup values.
+    config: Config,
+    tx: Sender<Message>,
+    state: State,
+    last_hash: Option<u64>,
+}
+
+impl ConfigWatcher {
+    pub fn new(cli: Cli, config: Config, tx: Sender<Message>, state: State) -> Self {
+        let mut watcher = Self {
+            cli,
+            config,
+            tx,
+            state,
+            last_hash: None,
+        };
+        watcher.last_hash = watcher.file_hash();
+        watcher
+    }
+
+    pub fn run(&mut self) {
+        while !self.state.is_cancelled() {
+            let hash = self.file_hash();
+            if hash != self.last_hash {
+                debug!(
+                    "{} changed, reloading it.",
+                    self.cli.config_file().display()
+                );
+                self.last_hash = hash;
+                self.reload();
+            }
+
+            self.state.sleep_unless_cancelled(POLL_INTERVAL);
+        }
+    }
+
+    fn file_hash(&self) -> Option<u64> {
+        fs::read(self.cli.config_file())
+            .ok()
+            .map(|contents| seahash::hash(&contents))
+    }
+
+    fn reload(&mut self) {
+        let config = match Config::load(&self.cli.figment()) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Not applying the changed config. {}", e);
+                return;
+            }
+        };
+        if config == self.config {
+            debug!("Config file changed, but the effective config did not.");
+            return;
+        }
+
+        if config.log_level != self.config.log_level {
+            logging::set_filters(&config.log_level);
+            info!("Log level is now '{}'", config.log_level);
+            self.config.log_level = config.log_level.clone();
+        }
+        if config.mappings != self.config.mappings {
+            info!("Applying changed button mappings");
+            let message = Message::MappingsChanged {
+                mappings: config.mappings.clone(),
+            };
+            if self.tx.send(message).is_err() {
+                return;
+            }
+            self.config.mappings = config.mappings.clone();
+        }
+        if config.streams != self.config.streams {
+            info!(
+                "Streams started from now on default to {:?}",
+                config.streams
+            );
+            *lock(&self.state.stream_defaults) = config.streams.clone();
+            self.config.streams = config.streams.clone();
+        }
+
+        let needing_restart = config.changes_needing_restart(&self.config);
+        if !needing_restart.is_empty() {
+            warn!(
+                "Changes to {} only take effect after restarting the server",
+                needing_restart.join(", ")
+            );
+        }
+    }
+}
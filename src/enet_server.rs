@@ -1,48 +1,163 @@
 use crate::{
+    VERSION,
+    config::TextureConfig,
+    error::{self, Error},
     messages::Message,
-    msgpack::{Command, ProtocolMessage},
-    state::{State, StreamKey},
-    texture_stream::{self, StreamOptions},
+    msgpack::{self, Command, ErrorCode, ProtocolMessage},
+    state::{ClientId, LinkQuality, RunningStream, State, lock},
+    texture_reader::TextureId,
+    texture_stream::{DeltaOptions, StreamOptions, TextureStream, Transform},
 };
 use enet::{Address, Enet, Host, Packet, Peer, PeerID};
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 
 use std::{
-    net::Ipv4Addr,
+    cell::RefCell,
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
     rc::Rc,
     sync::{
-        Arc, Mutex,
-        atomic::{AtomicBool, Ordering},
-        mpsc::{self, Receiver, Sender},
+        Mutex,
+        atomic::Ordering,
+        mpsc::{Receiver, Sender},
     },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 pub struct EnetServer {
-    address: String,
+    address: Ipv4Addr,
     port: u16,
+    texture_config: TextureConfig,
     state: State,
     callback_tx: Sender<Message>,
+    packet_tx: Sender<Outgoing>,
+    packet_rx: Receiver<Outgoing>,
+    clients: RefCell<Clients>,
 }
 
-pub struct WrappedHost {
+/// The ENet peer behind each client, everything outside of this module only knows the `ClientId`.
+#[derive(Default)]
+struct Clients {
+    last_id: u64,
+    peers: HashMap<ClientId, PeerID>,
+    ids: HashMap<PeerID, ClientId>,
+}
+
+impl Clients {
+    fn connected(&mut self, peer_id: PeerID) -> ClientId {
+        self.last_id += 1;
+        let id = ClientId(self.last_id);
+        self.peers.insert(id, peer_id);
+        self.ids.insert(peer_id, id);
+        id
+    }
+
+    fn disconnected(&mut self, peer_id: PeerID) -> Option<ClientId> {
+        let id = self.ids.remove(&peer_id)?;
+        self.peers.remove(&id);
+        Some(id)
+    }
+
+    fn id(&self, peer_id: PeerID) -> Option<ClientId> {
+        self.ids.get(&peer_id).copied()
+    }
+
+    fn peer(&self, id: ClientId) -> Option<PeerID> {
+        self.peers.get(&id).copied()
+    }
+
+    /// Forgets all peers, but not which ids were handed out already.
+    fn clear(&mut self) {
+        self.peers.clear();
+        self.ids.clear();
+    }
+}
+
+pub struct WrappedHost<'a> {
     host: Rc<Mutex<Host<PeerData>>>,
-    rx: Receiver<PacketData>,
+    rx: &'a Receiver<Outgoing>,
+}
+
+/// The channel control messages (handshake, replies) are sent on,
+/// texture streams use the channel of their `TextureId`.
+pub const CONTROL_CHANNEL: u8 = 0;
+
+/// Everything the enet thread is asked to do by other threads.
+#[derive(Debug)]
+pub enum Outgoing {
+    Packet(PacketData),
+    /// Disconnect the peer once all previously queued packets went out.
+    Disconnect {
+        peer_id: ClientId,
+        reason: DisconnectReason,
+    },
+}
+
+/// Sent as the data of the disconnect event, so clients can tell why they got dropped.
+#[derive(Debug, Clone, Copy)]
+#[repr(u32)]
+pub enum DisconnectReason {
+    IncompatibleProtocol = 1,
+    ShuttingDown = 2,
+}
+
+/// How long to wait for peers to acknowledge the disconnect when shutting down.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often round trip times and packet loss of the peers are looked at.
+const LINK_STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// ENet reports packet loss as a fraction of this.
+const PACKET_LOSS_SCALE: f32 = 65536.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    Reliable,
+    Unreliable,
 }
 
 #[derive(Clone, Debug)]
 pub struct PacketData {
-    pub peer_id: PeerID,
+    pub peer_id: ClientId,
     pub data: Vec<u8>,
     pub channel: u8,
+    pub delivery: Delivery,
+}
+
+impl PacketData {
+    /// Wraps a protocol message to be sent reliably on the control channel.
+    pub fn message(
+        peer_id: ClientId,
+        message: &ProtocolMessage,
+    ) -> Result<Self, rmp_serde::encode::Error> {
+        Ok(Self {
+            peer_id,
+            data: rmp_serde::to_vec_named(message)?,
+            channel: CONTROL_CHANNEL,
+            delivery: Delivery::Reliable,
+        })
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Hash)]
 pub struct PeerData {}
 
-impl WrappedHost {
-    pub fn new(host: Host<PeerData>, rx: Receiver<PacketData>) -> Self {
+/// Queues `message` to be sent to the peer, logging instead of failing since there
+/// is nobody to report the failure to.
+pub fn send_message(tx: &Sender<Outgoing>, peer_id: ClientId, message: &ProtocolMessage) {
+    match PacketData::message(peer_id, message) {
+        Ok(packet_data) => {
+            if let Err(e) = tx.send(Outgoing::Packet(packet_data)) {
+                error!("Failed to queue message for {:?}: {}", peer_id, e);
+            }
+        }
+        Err(e) => error!("Failed to serialize message {:?}: {}", message, e),
+    }
+}
+
+impl<'a> WrappedHost<'a> {
+    pub fn new(host: Host<PeerData>, rx: &'a Receiver<Outgoing>) -> Self {
         WrappedHost {
             host: Rc::new(Mutex::new(host)),
             rx,
@@ -51,49 +166,96 @@ impl WrappedHost {
 
     /// enet is fighting really hard against being used from more than one thread, and that's okay
     /// so here is code to shuttle packets before calling host.service() again.
-    pub fn queue_packets_to_send(&self) {
+    fn queue_packets_to_send(&self, clients: &Clients) {
         loop {
-            let to_send = self.rx.recv_timeout(Duration::from_millis(1));
-            if let Ok(to_send) = to_send {
-                let mut host = self
-                    .host
-                    .lock()
-                    .expect("Could not lock host to send packet");
-                if let Some(peer) = host.peer_mut(to_send.peer_id) {
-                    let packet = Packet::new(
-                        to_send.data,
-                        enet::PacketMode::UnreliableUnsequencedUnreliablyFragmented,
-                    )
-                    .expect("Failed to create enet packet");
-
-                    let result = peer.send_packet(packet, to_send.channel);
-
-                    match result {
-                        Ok(_) => trace!("Queued packet!"),
-                        Err(e) => error!("Failed to send packet because of: {}", e),
+            let outgoing = self.rx.recv_timeout(Duration::from_millis(1));
+            let Ok(outgoing) = outgoing else {
+                break;
+            };
+            let mut host = self
+                .host
+                .lock()
+                .expect("Could not lock host to send packet");
+            match outgoing {
+                Outgoing::Packet(to_send) => {
+                    if let Some(peer) = clients
+                        .peer(to_send.peer_id)
+                        .and_then(|id| host.peer_mut(id))
+                    {
+                        let mode = match to_send.delivery {
+                            Delivery::Reliable => enet::PacketMode::ReliableSequenced,
+                            Delivery::Unreliable => {
+                                enet::PacketMode::UnreliableUnsequencedUnreliablyFragmented
+                            }
+                        };
+                        let packet =
+                            Packet::new(to_send.data, mode).expect("Failed to create enet packet");
+
+                        let result = peer.send_packet(packet, to_send.channel);
+
+                        match result {
+                            Ok(_) => trace!("Queued packet!"),
+                            Err(e) => error!("Failed to send packet because of: {}", e),
+                        }
+                    }
+                }
+                Outgoing::Disconnect { peer_id, reason } => {
+                    if let Some(peer) = clients.peer(peer_id).and_then(|id| host.peer_mut(id)) {
+                        info!("Disconnecting peer {:?} because of {:?}", peer_id, reason);
+                        peer.disconnect_later(reason as u32);
                     }
                 }
-            } else {
-                break;
             }
         }
     }
 }
 
 impl EnetServer {
-    pub fn new(tx: Sender<Message>, address: String, port: u16, state: State) -> Self {
-        Self {
+    pub fn new(
+        tx: Sender<Message>,
+        packet_tx: Sender<Outgoing>,
+        packet_rx: Receiver<Outgoing>,
+        address: String,
+        port: u16,
+        texture_config: TextureConfig,
+        state: State,
+    ) -> error::Result<Self> {
+        // ENet only speaks IPv4, listening on all IPv6 addresses means listening on all IPv4 ones.
+        let address = match address.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => ip,
+            Ok(IpAddr::V6(ip)) if ip.is_unspecified() => {
+                info!("ENet only supports IPv4, accepting connections on all IPv4 addresses");
+                Ipv4Addr::UNSPECIFIED
+            }
+            _ => {
+                return Err(Error::InvalidAddress {
+                    key: "listen_address",
+                    value: address,
+                });
+            }
+        };
+
+        Ok(Self {
             address,
             port,
+            texture_config,
             state,
             callback_tx: tx,
-        }
+            packet_tx,
+            packet_rx,
+            clients: RefCell::default(),
+        })
     }
 
-    pub fn run(&self) {
-        let ipv4 = self.address.parse::<Ipv4Addr>().unwrap();
-        let address = Address::new(ipv4, self.port);
-        let enet = Enet::new().expect("Failed to setup enet");
+    pub fn run(&self) -> error::Result<()> {
+        self.forget_peers();
+
+        let address = Address::new(self.address, self.port);
+        let enet_error = |e: enet::Error| Error::Enet {
+            address: format!("{}:{}", self.address, self.port),
+            message: e.to_string(),
+        };
+        let enet = Enet::new().map_err(enet_error)?;
         let host: Host<PeerData> = enet
             .create_host(
                 Some(&address),
@@ -102,23 +264,22 @@ impl EnetServer {
                 enet::BandwidthLimit::Unlimited,
                 enet::BandwidthLimit::Unlimited,
             )
-            .expect("Failed to create enet host");
+            .map_err(enet_error)?;
 
         info!(
             "Listening for connections on: {}:{}",
             self.address, self.port
         );
 
-        let (tx, rx) = mpsc::channel();
-
-        let wrapped_host = WrappedHost::new(host, rx);
+        let wrapped_host = WrappedHost::new(host, &self.packet_rx);
+        let mut next_link_stats = Instant::now();
 
         loop {
             if self.state.cancellation_token.load(Ordering::Relaxed) {
                 break;
             }
 
-            wrapped_host.queue_packets_to_send();
+            wrapped_host.queue_packets_to_send(&self.clients.borrow());
 
             let mut locked_host = wrapped_host.host.lock().unwrap();
 
@@ -128,27 +289,47 @@ impl EnetServer {
                     if let Some(mut event) = service_result {
                         match event.kind() {
                             enet::EventKind::Connect => {
-                                info!("Peer connected: {:?}", event.peer_id());
+                                let peer_id = self.clients.borrow_mut().connected(event.peer_id());
+                                info!("Peer connected: {:?}", peer_id);
+                                lock(&self.state.connected_peers).insert(peer_id);
                                 let peer: &mut Peer<PeerData> = event.peer_mut();
                                 peer.set_ping_interval(Duration::from_millis(200));
                             }
                             enet::EventKind::Disconnect { data: _ } => {
-                                info!("Peer disconnected: {:?}", event.peer_id());
-                                self.state.cancel_all_streams(event.peer_id());
+                                let Some(peer_id) =
+                                    self.clients.borrow_mut().disconnected(event.peer_id())
+                                else {
+                                    continue;
+                                };
+                                info!("Peer disconnected: {:?}", peer_id);
+                                lock(&self.state.connected_peers).remove(&peer_id);
+                                self.state.cancel_all_streams(peer_id);
+                                let _ =
+                                    self.callback_tx.send(Message::PeerDisconnected { peer_id });
                             }
                             enet::EventKind::Receive { channel_id, packet } => {
+                                let Some(peer_id) = self.clients.borrow().id(event.peer_id())
+                                else {
+                                    continue;
+                                };
                                 let payload = packet.data();
                                 let message: Result<ProtocolMessage, rmp_serde::decode::Error> =
                                     rmp_serde::from_slice(payload);
                                 match message {
-                                    Ok(message) => self.handle_message(
-                                        tx.clone(),
-                                        event.peer_id(),
-                                        *channel_id,
-                                        message,
-                                    ),
+                                    Ok(message) => {
+                                        self.handle_message(peer_id, *channel_id, message)
+                                    }
                                     Err(e) => {
                                         error!("Failed to parse message due to: {}", e);
+                                        send_message(
+                                            &self.packet_tx,
+                                            peer_id,
+                                            &ProtocolMessage::error(
+                                                None,
+                                                ErrorCode::InvalidMessage,
+                                                format!("Failed to parse message: {}", e),
+                                            ),
+                                        );
                                     }
                                 }
                             }
@@ -158,67 +339,306 @@ impl EnetServer {
                 Err(e) => error!("Failed to service host: {}", e),
             }
 
+            if Instant::now() >= next_link_stats {
+                self.update_link_stats(&mut locked_host);
+                next_link_stats = Instant::now() + LINK_STATS_INTERVAL;
+            }
+
             thread::sleep(Duration::from_millis(1));
         }
 
         info!("Shutting down enet server");
+        self.shutdown(&wrapped_host);
+        Ok(())
     }
 
-    fn handle_message(
-        &self,
-        tx: Sender<PacketData>,
-        peer_id: PeerID,
-        _channel_id: u8,
-        message: ProtocolMessage,
-    ) {
+    /// Records how well every peer's connection is doing, for streams to adapt to.
+    fn update_link_stats(&self, host: &mut Host<PeerData>) {
+        let peers = lock(&self.state.connected_peers).clone();
+        let mut link_stats = lock(&self.state.link_stats);
+        link_stats.retain(|peer_id, _| peers.contains(peer_id));
+        let clients = self.clients.borrow();
+        for peer_id in peers {
+            if let Some(peer) = clients.peer(peer_id).and_then(|id| host.peer_mut(id)) {
+                let link_quality = LinkQuality {
+                    round_trip_time: peer.mean_rtt(),
+                    packet_loss: peer.packet_loss() as f32 / PACKET_LOSS_SCALE,
+                };
+                trace!("Link to {:?}: {:?}", peer_id, link_quality);
+                link_stats.insert(peer_id, link_quality);
+            }
+        }
+    }
+
+    /// Cleans up after peers of a previous run, in case the server got restarted.
+    fn forget_peers(&self) {
+        self.state.stop_all_streams();
+        self.clients.borrow_mut().clear();
+        let peers: Vec<ClientId> = lock(&self.state.connected_peers).drain().collect();
+        for peer_id in peers {
+            let _ = self.callback_tx.send(Message::PeerDisconnected { peer_id });
+        }
+    }
+
+    /// Stops all streams and tells every peer we're going away.
+    fn shutdown(&self, wrapped_host: &WrappedHost) {
+        self.state.stop_all_streams();
+
+        let mut host = wrapped_host.host.lock().unwrap();
+        let clients = self.clients.borrow();
+        let mut peers = lock(&self.state.connected_peers).clone();
+        for peer_id in peers.iter() {
+            if let Some(peer) = clients.peer(*peer_id).and_then(|id| host.peer_mut(id)) {
+                peer.disconnect(DisconnectReason::ShuttingDown as u32);
+            }
+        }
+
+        let deadline = Instant::now() + DISCONNECT_TIMEOUT;
+        while !peers.is_empty() && Instant::now() < deadline {
+            match host.service(Duration::from_millis(10)) {
+                Ok(Some(event)) => {
+                    if let enet::EventKind::Disconnect { data: _ } = event.kind()
+                        && let Some(peer_id) = clients.id(event.peer_id())
+                    {
+                        debug!("Peer disconnected: {:?}", peer_id);
+                        peers.remove(&peer_id);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Failed to service host while shutting down: {}", e);
+                    break;
+                }
+            }
+        }
+
+        if !peers.is_empty() {
+            info!("Peers {:?} did not acknowledge the disconnect", peers);
+        }
+    }
+
+    fn handle_message(&self, peer_id: ClientId, _channel_id: u8, message: ProtocolMessage) {
         match message {
+            ProtocolMessage::Hello { protocol_version } => {
+                self.handle_hello(peer_id, protocol_version)
+            }
+            ProtocolMessage::GetHealth { correlation_id } => {
+                let subsystems = lock(&self.state.health).values().cloned().collect();
+                send_message(
+                    &self.packet_tx,
+                    peer_id,
+                    &ProtocolMessage::Health {
+                        correlation_id,
+                        subsystems,
+                    },
+                );
+            }
             ProtocolMessage::StreamedTextureRequest {
                 identifier,
                 command,
                 refresh_rate,
                 quality,
-            } => match command {
-                Command::Start => {
-                    let token = Arc::new(AtomicBool::new(false));
-                    let key = StreamKey {
-                        peer_id,
-                        identifier,
-                    };
-
-                    let mut streams = self.state.streams_running.lock().unwrap();
-                    streams.insert(key.clone(), token.clone());
-                    let stream_options = StreamOptions::new(refresh_rate, quality);
-                    debug!("starting: {:?}:{:?}", key, stream_options);
-                    let mut texture_stream =
-                        texture_stream::TextureStream::new(token, key, stream_options, tx);
-
-                    let _ = thread::spawn(move || {
-                        texture_stream.run();
-                    });
-                }
-                Command::Stop => {
-                    let key = StreamKey {
-                        peer_id,
-                        identifier,
-                    };
-                    let mut streams = self.state.streams_running.lock().unwrap();
-                    if let Some(token) = streams.get(&key) {
-                        token.store(true, Ordering::Relaxed);
+                encoding,
+                subsampling,
+                size,
+                resize,
+                crop,
+                delta,
+                adaptive,
+                correlation_id,
+            } => {
+                let Some(texture_id) = self.texture_id(peer_id, &identifier, correlation_id) else {
+                    return;
+                };
+
+                match command {
+                    Command::Start => {
+                        let stream_options = StreamOptions::new(
+                            refresh_rate,
+                            quality,
+                            encoding,
+                            subsampling,
+                            Transform::new(size, resize, crop),
+                            delta.map(DeltaOptions::new),
+                            &lock(&self.state.stream_defaults),
+                        )
+                        .and_then(|options| options.adaptive(adaptive));
+                        let stream_options = match stream_options {
+                            Ok(stream_options) => stream_options,
+                            Err(message) => {
+                                error!(
+                                    "Not streaming {} to {:?}: {}",
+                                    identifier, peer_id, message
+                                );
+                                send_message(
+                                    &self.packet_tx,
+                                    peer_id,
+                                    &ProtocolMessage::error(
+                                        correlation_id,
+                                        ErrorCode::InvalidValue,
+                                        message,
+                                    ),
+                                );
+                                return;
+                            }
+                        };
+                        debug!(
+                            "starting: {:?}:{:?}:{:?}",
+                            peer_id, texture_id, stream_options
+                        );
+
+                        let mut streams = lock(&self.state.streams_running);
+                        if streams
+                            .get(&texture_id)
+                            .is_some_and(RunningStream::is_finished)
+                            && let Some(dead) = streams.remove(&texture_id)
+                        {
+                            warn!("The capture of {:?} died, starting it again", texture_id);
+                            let stream = self.start_capture(texture_id);
+                            *lock(&stream.subscribers) = lock(&dead.subscribers).clone();
+                            streams.insert(texture_id, stream);
+                        }
+                        let stream = streams
+                            .entry(texture_id)
+                            .or_insert_with(|| self.start_capture(texture_id));
+                        lock(&stream.subscribers).insert(peer_id, stream_options);
+
+                        self.acknowledge(peer_id, correlation_id);
                     }
-                    streams.remove(&key);
+                    Command::Stop => {
+                        self.state.cancel_stream(texture_id, peer_id);
 
-                    debug!("stopping: {:?}", key);
+                        debug!("stopping: {:?}:{:?}", peer_id, texture_id);
+                        self.acknowledge(peer_id, correlation_id);
+                    }
+                }
+            }
+            ProtocolMessage::RequestKeyframe {
+                identifier,
+                correlation_id,
+            } => {
+                let Some(texture_id) = self.texture_id(peer_id, &identifier, correlation_id) else {
+                    return;
+                };
+                if self.state.request_keyframe(texture_id, peer_id) {
+                    self.acknowledge(peer_id, correlation_id);
+                } else {
+                    send_message(
+                        &self.packet_tx,
+                        peer_id,
+                        &ProtocolMessage::error(
+                            correlation_id,
+                            ErrorCode::NotStreaming,
+                            format!("Not streaming '{}'", identifier),
+                        ),
+                    );
                 }
-            },
+            }
             msg => {
                 debug!("Sending message to callback tx: {:?}", msg);
-                let result = self
-                    .callback_tx
-                    .send(Message::EnetReceived { message: msg });
+                let result = self.callback_tx.send(Message::EnetReceived {
+                    peer_id,
+                    message: msg,
+                });
                 if let Err(e) = result {
                     error!("Failed to send callback message: {}", e);
                 }
             }
         }
     }
+
+    fn start_capture(&self, texture_id: TextureId) -> RunningStream {
+        debug!("starting capture of {:?}", texture_id);
+        let mut stream = RunningStream::new();
+        let mut texture_stream = TextureStream::new(
+            stream.cancellation_token.clone(),
+            texture_id,
+            stream.subscribers.clone(),
+            stream.keyframe_requests.clone(),
+            self.state.link_stats.clone(),
+            self.texture_config.clone(),
+            self.packet_tx.clone(),
+        );
+        stream.thread = Some(thread::spawn(move || {
+            texture_stream.run();
+        }));
+        stream
+    }
+
+    fn acknowledge(&self, peer_id: ClientId, correlation_id: Option<u32>) {
+        if correlation_id.is_some() {
+            send_message(
+                &self.packet_tx,
+                peer_id,
+                &ProtocolMessage::Acknowledged { correlation_id },
+            );
+        }
+    }
+
+    /// Looks up the texture, telling the peer if there is no such texture.
+    fn texture_id(
+        &self,
+        peer_id: ClientId,
+        identifier: &str,
+        correlation_id: Option<u32>,
+    ) -> Option<TextureId> {
+        let texture_id = TextureId::from(identifier);
+        if let TextureId::Unknown = texture_id {
+            error!("Received unknown texture identifier: {}", identifier);
+            send_message(
+                &self.packet_tx,
+                peer_id,
+                &ProtocolMessage::error(
+                    correlation_id,
+                    ErrorCode::UnknownIdentifier,
+                    format!("Unknown texture identifier '{}'", identifier),
+                ),
+            );
+            return None;
+        }
+        Some(texture_id)
+    }
+
+    fn handle_hello(&self, peer_id: ClientId, protocol_version: Option<u16>) {
+        let compatibility = msgpack::check_compatibility(protocol_version);
+        let reply = match &compatibility {
+            Ok(()) => {
+                info!(
+                    "Peer {:?} speaks protocol version {:?}",
+                    peer_id, protocol_version
+                );
+                ProtocolMessage::Welcome {
+                    protocol_version: msgpack::PROTOCOL_VERSION,
+                    server_version: VERSION.to_string(),
+                    messages: msgpack::SUPPORTED_MESSAGES
+                        .iter()
+                        .map(|m| m.to_string())
+                        .collect(),
+                    textures: TextureId::ALL
+                        .iter()
+                        .map(|t| t.identifier().to_string())
+                        .collect(),
+                    encodings: msgpack::SUPPORTED_ENCODINGS
+                        .iter()
+                        .map(|e| e.to_string())
+                        .collect(),
+                }
+            }
+            Err(reason) => {
+                error!("Rejecting peer {:?}: {}", peer_id, reason);
+                ProtocolMessage::Rejected {
+                    reason: reason.clone(),
+                }
+            }
+        };
+
+        send_message(&self.packet_tx, peer_id, &reply);
+
+        if compatibility.is_err() {
+            let _ = self.packet_tx.send(Outgoing::Disconnect {
+                peer_id,
+                reason: DisconnectReason::IncompatibleProtocol,
+            });
+        }
+    }
 }
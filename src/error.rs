@@ -0,0 +1,86 @@
+use std::fmt;
+
+/// Everything that keeps the server from starting, worded for whoever launched it.
+#[derive(Debug)]
+pub enum Error {
+    /// The configuration could not be read, or a value has the wrong type.
+    Config(Box<figment::Error>),
+    /// The effective configuration could not be printed.
+    PrintConfig(toml::ser::Error),
+    /// An address in the configuration could not be parsed.
+    InvalidAddress { key: &'static str, value: String },
+    /// A socket could not be bound, usually because the port is taken.
+    Bind {
+        what: &'static str,
+        address: String,
+        source: std::io::Error,
+    },
+    /// ENet could not be set up, or could not listen on the address.
+    Enet { address: String, message: String },
+    /// A subsystem failed in a way that restarting it won't fix.
+    Subsystem {
+        name: &'static str,
+        source: Box<Error>,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(e) => {
+                write!(f, "Invalid configuration:")?;
+                for error in e.as_ref().clone() {
+                    write!(f, "\n  - {}", error)?;
+                }
+                Ok(())
+            }
+            Error::PrintConfig(e) => write!(f, "Could not print the configuration: {}", e),
+            Error::InvalidAddress { key, value } => write!(
+                f,
+                "Invalid configuration: '{}' is not a valid address for `{}`",
+                value, key
+            ),
+            Error::Bind {
+                what,
+                address,
+                source,
+            } => write!(
+                f,
+                "Could not listen for {} on {}: {}. Is another instance already running, or the port taken by another program?",
+                what, address, source
+            ),
+            Error::Enet { address, message } => write!(
+                f,
+                "Could not listen for connections on {}: {}. Is another instance already running, or the port taken by another program?",
+                address, message
+            ),
+            Error::Subsystem { name, source } => write!(f, "The {} failed: {}", name, source),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Config(e) => Some(e.as_ref()),
+            Error::PrintConfig(e) => Some(e),
+            Error::Bind { source, .. } => Some(source),
+            Error::Subsystem { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<figment::Error> for Error {
+    fn from(e: figment::Error) -> Self {
+        Error::Config(Box::new(e))
+    }
+}
+
+impl From<toml::ser::Error> for Error {
+    fn from(e: toml::ser::Error) -> Self {
+        Error::PrintConfig(e)
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use log::info;
+
+use crate::config::InputBackendKind;
+use crate::key_strokes::KeyStroke;
+
+/// How many key strokes the recording backend keeps, older ones are dropped.
+pub const MAX_RECORDED_STROKES: usize = 1000;
+
+/// The key strokes a `RecordingInputBackend` recorded, oldest first.
+pub type RecordedStrokes = Arc<Mutex<VecDeque<KeyStroke>>>;
+
+/// Whatever delivers key strokes to BMS.
+pub trait InputBackend: Send {
+    /// Makes sure the keys sent next end up in BMS.
+    fn focus_target(&mut self) -> InputResult<()>;
+
+    fn send(&mut self, strokes: &[KeyStroke]) -> InputResult<()>;
+}
+
+pub fn create_input_backend(kind: InputBackendKind) -> Box<dyn InputBackend> {
+    match kind {
+        #[cfg(windows)]
+        InputBackendKind::SendInput => Box::new(crate::keyboard_emulator::SendInputBackend::new()),
+        #[cfg(not(windows))]
+        InputBackendKind::SendInput => {
+            log::warn!("SendInput is only available on Windows, recording key strokes instead");
+            Box::new(RecordingInputBackend::new(Arc::default()))
+        }
+        InputBackendKind::Recording => Box::new(RecordingInputBackend::new(Arc::default())),
+    }
+}
+
+/// Records key strokes instead of sending them, for working without BMS or Windows.
+/// Whoever created it can look at the exact sequence through the shared `strokes`,
+/// which holds the last `MAX_RECORDED_STROKES` of them.
+pub struct RecordingInputBackend {
+    strokes: RecordedStrokes,
+}
+
+impl RecordingInputBackend {
+    pub fn new(strokes: RecordedStrokes) -> Self {
+        Self { strokes }
+    }
+}
+
+impl InputBackend for RecordingInputBackend {
+    fn focus_target(&mut self) -> InputResult<()> {
+        Ok(())
+    }
+
+    fn send(&mut self, strokes: &[KeyStroke]) -> InputResult<()> {
+        info!("Recording {:?}", strokes);
+        let mut recorded = self.strokes.lock().unwrap();
+        recorded.extend(strokes.iter().cloned());
+        let excess = recorded.len().saturating_sub(MAX_RECORDED_STROKES);
+        recorded.drain(..excess);
+        Ok(())
+    }
+}
+
+pub type InputResult<T> = Result<T, InputError>;
+
+/// Error when simulating input
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(not(windows), allow(dead_code))]
+pub enum InputError {
+    /// The window to send input to could not be found
+    TargetNotFound,
+    /// There was an error with the protocol
+    Simulate(&'static str),
+    /// The input you want to simulate is invalid
+    /// This happens for example if you want to enter text that contains NULL
+    /// bytes (`\0`)
+    InvalidInput(&'static str),
+}
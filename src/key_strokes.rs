@@ -0,0 +1,319 @@
+use std::{collections::HashMap, time::Duration};
+
+use falcon_key_file::{Callback, Key, Modifier};
+
+/// One step of the key sequence that invokes a callback.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyStroke {
+    Key {
+        scan: ScanCode,
+        direction: Direction,
+        extended: bool,
+    },
+    /// Give BMS a moment to notice the previous keys.
+    Pause(Duration),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Press,
+    Release,
+    Click,
+}
+
+pub type ScanCode = u16;
+
+/// The keys a callback is bound to, as scan codes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyBinding {
+    pub key: ScanCode,
+    pub extended: bool,
+    pub modifiers: Vec<ScanCode>,
+    /// Clicked before `key` is pressed, for callbacks bound to a key combination.
+    pub combo_key: Option<ScanCode>,
+    pub combo_extended: bool,
+    pub combo_modifiers: Vec<ScanCode>,
+}
+
+impl From<&Callback> for KeyBinding {
+    fn from(callback: &Callback) -> Self {
+        Self {
+            key: callback.key_code,
+            extended: is_extended_key(&callback.readable_key_code),
+            modifiers: callback.modifiers.iter().map(to_key).collect(),
+            combo_key: (callback.combo_key_code != 0).then_some(callback.combo_key_code),
+            combo_extended: is_extended_key(&callback.readable_combo_key_code),
+            combo_modifiers: callback.combo_modifiers.iter().map(to_key).collect(),
+        }
+    }
+}
+
+/// Counts how many held down controls need each modifier, so that letting go of one
+/// of them does not release a modifier another one is still holding down.
+#[derive(Debug, Default)]
+pub struct HeldModifiers {
+    counts: HashMap<ScanCode, usize>,
+}
+
+impl HeldModifiers {
+    /// The key sequence that presses `binding` and holds its key and modifiers down until
+    /// `release` is called with it. The combo key, if it has one, is clicked beforehand.
+    pub fn press(&mut self, binding: &KeyBinding) -> Vec<KeyStroke> {
+        let mut strokes = vec![];
+
+        if let Some(combo_key) = binding.combo_key {
+            let modifiers = self.not_held(&binding.combo_modifiers);
+            strokes.extend(modifiers_down(&modifiers));
+            strokes.push(key_stroke(
+                combo_key,
+                Direction::Click,
+                binding.combo_extended,
+            ));
+            strokes.extend(modifiers_up(&modifiers));
+            strokes.push(KeyStroke::Pause(Duration::from_millis(30)));
+        }
+
+        strokes.extend(modifiers_down(&self.not_held(&binding.modifiers)));
+        for modifier in &binding.modifiers {
+            *self.counts.entry(*modifier).or_default() += 1;
+        }
+        strokes.push(key_stroke(binding.key, Direction::Press, binding.extended));
+        strokes
+    }
+
+    /// The key sequence that lets go of what `press` held down, except for modifiers
+    /// other controls are still holding.
+    pub fn release(&mut self, binding: &KeyBinding) -> Vec<KeyStroke> {
+        let mut released = vec![];
+        for modifier in &binding.modifiers {
+            if let Some(count) = self.counts.get_mut(modifier) {
+                *count -= 1;
+                if *count == 0 {
+                    self.counts.remove(modifier);
+                    released.push(*modifier);
+                }
+            }
+        }
+
+        let mut strokes = vec![key_stroke(
+            binding.key,
+            Direction::Release,
+            binding.extended,
+        )];
+        strokes.extend(modifiers_up(&released));
+        strokes
+    }
+
+    fn not_held(&self, modifiers: &[ScanCode]) -> Vec<ScanCode> {
+        modifiers
+            .iter()
+            .filter(|modifier| !self.counts.contains_key(modifier))
+            .copied()
+            .collect()
+    }
+}
+
+fn modifiers_down(modifiers: &[ScanCode]) -> Vec<KeyStroke> {
+    let mut strokes: Vec<_> = modifiers
+        .iter()
+        .map(|modifier| key_stroke(*modifier, Direction::Press, false))
+        .collect();
+    strokes.push(KeyStroke::Pause(Duration::from_millis(10)));
+    strokes
+}
+
+fn modifiers_up(modifiers: &[ScanCode]) -> Vec<KeyStroke> {
+    modifiers
+        .iter()
+        .rev()
+        .map(|modifier| key_stroke(*modifier, Direction::Release, false))
+        .collect()
+}
+
+fn key_stroke(scan: ScanCode, direction: Direction, extended: bool) -> KeyStroke {
+    KeyStroke::Key {
+        scan,
+        direction,
+        extended,
+    }
+}
+
+fn is_extended_key(key: &Key) -> bool {
+    // THE special snowflake keycode
+    *key == Key::NumpadEnter
+}
+
+fn to_key(m: &Modifier) -> ScanCode {
+    match m {
+        // we're going straight to the raw scancode for these, as per here:
+        // https://gist.github.com/arithex/3e953d1eb096afe58ce05ba6846493e4
+        Modifier::LSHIFT => LSHIFT,
+        Modifier::LCONTROL => LCONTROL,
+        Modifier::LALT => LALT,
+    }
+}
+
+const LSHIFT: ScanCode = 0x2A;
+const LCONTROL: ScanCode = 0x1D;
+const LALT: ScanCode = 0x38;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::input_backend::{InputBackend, RecordingInputBackend};
+
+    const KEY_T: ScanCode = 0x14;
+    const KEY_W: ScanCode = 0x11;
+    const NUMPAD_ENTER: ScanCode = 0x1C;
+
+    fn binding(key: ScanCode, modifiers: &[ScanCode]) -> KeyBinding {
+        KeyBinding {
+            key,
+            extended: false,
+            modifiers: modifiers.to_vec(),
+            combo_key: None,
+            combo_extended: false,
+            combo_modifiers: vec![],
+        }
+    }
+
+    fn down(scan: ScanCode) -> KeyStroke {
+        key_stroke(scan, Direction::Press, false)
+    }
+
+    fn up(scan: ScanCode) -> KeyStroke {
+        key_stroke(scan, Direction::Release, false)
+    }
+
+    fn pause(millis: u64) -> KeyStroke {
+        KeyStroke::Pause(Duration::from_millis(millis))
+    }
+
+    /// Sends the press and then the release of `binding`, returning what was recorded.
+    fn record_click(binding: &KeyBinding) -> Vec<KeyStroke> {
+        let recorded = Arc::default();
+        let mut backend = RecordingInputBackend::new(Arc::clone(&recorded));
+        let mut held = HeldModifiers::default();
+        backend.focus_target().unwrap();
+        backend.send(&held.press(binding)).unwrap();
+        backend.send(&held.release(binding)).unwrap();
+        recorded.lock().unwrap().iter().cloned().collect()
+    }
+
+    #[test]
+    fn holds_modifiers_around_the_key() {
+        let strokes = record_click(&binding(KEY_T, &[LCONTROL, LALT]));
+
+        assert_eq!(
+            strokes,
+            vec![
+                down(LCONTROL),
+                down(LALT),
+                pause(10),
+                down(KEY_T),
+                up(KEY_T),
+                up(LALT),
+                up(LCONTROL),
+            ]
+        );
+    }
+
+    #[test]
+    fn clicks_the_combo_key_first() {
+        let binding = KeyBinding {
+            combo_key: Some(KEY_W),
+            combo_modifiers: vec![LSHIFT],
+            ..binding(KEY_T, &[])
+        };
+
+        assert_eq!(
+            record_click(&binding),
+            vec![
+                down(LSHIFT),
+                pause(10),
+                key_stroke(KEY_W, Direction::Click, false),
+                up(LSHIFT),
+                pause(30),
+                pause(10),
+                down(KEY_T),
+                up(KEY_T),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_extended_keys_extended() {
+        let binding = KeyBinding {
+            extended: true,
+            ..binding(NUMPAD_ENTER, &[])
+        };
+
+        assert_eq!(
+            record_click(&binding),
+            vec![
+                pause(10),
+                key_stroke(NUMPAD_ENTER, Direction::Press, true),
+                key_stroke(NUMPAD_ENTER, Direction::Release, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_modifiers_down_while_another_control_holds_them() {
+        let mut held = HeldModifiers::default();
+        let first = binding(KEY_T, &[LSHIFT]);
+        let second = binding(KEY_W, &[LSHIFT, LALT]);
+
+        assert_eq!(
+            held.press(&first),
+            vec![down(LSHIFT), pause(10), down(KEY_T)]
+        );
+        assert_eq!(
+            held.press(&second),
+            vec![down(LALT), pause(10), down(KEY_W)]
+        );
+        assert_eq!(held.release(&first), vec![up(KEY_T)]);
+        assert_eq!(held.release(&second), vec![up(KEY_W), up(LALT), up(LSHIFT)]);
+    }
+
+    #[test]
+    fn combos_leave_held_modifiers_alone() {
+        let mut held = HeldModifiers::default();
+        let holding = binding(KEY_T, &[LCONTROL]);
+        let combo = KeyBinding {
+            combo_key: Some(KEY_W),
+            combo_modifiers: vec![LCONTROL],
+            ..binding(NUMPAD_ENTER, &[])
+        };
+
+        held.press(&holding);
+        assert_eq!(
+            held.press(&combo),
+            vec![
+                pause(10),
+                key_stroke(KEY_W, Direction::Click, false),
+                pause(30),
+                pause(10),
+                down(NUMPAD_ENTER),
+            ]
+        );
+        assert_eq!(held.release(&combo), vec![up(NUMPAD_ENTER)]);
+        assert_eq!(held.release(&holding), vec![up(KEY_T), up(LCONTROL)]);
+    }
+
+    #[test]
+    fn recording_keeps_only_the_latest_strokes() {
+        let recorded: Arc<Mutex<_>> = Arc::default();
+        let mut backend = RecordingInputBackend::new(Arc::clone(&recorded));
+        let click = HeldModifiers::default().press(&binding(KEY_T, &[]));
+        for _ in 0..10_000 {
+            backend.send(&click).unwrap();
+        }
+
+        let recorded = recorded.lock().unwrap();
+        assert!(recorded.len() <= crate::input_backend::MAX_RECORDED_STROKES);
+        assert_eq!(recorded.back(), Some(&down(KEY_T)));
+    }
+}
@@ -1,10 +1,11 @@
-use falcon_key_file::Callback;
-use falcon_key_file::Key;
-use falcon_key_file::Modifier;
 use log::*;
+use std::ffi::CString;
 use std::thread;
 use std::time::Duration;
 
+use crate::input_backend::{InputBackend, InputError, InputResult};
+use crate::key_strokes::{Direction, KeyStroke, ScanCode};
+
 use windows::Win32::UI::Input::KeyboardAndMouse::{
     INPUT, INPUT_0, INPUT_KEYBOARD, KEYBD_EVENT_FLAGS, KEYBDINPUT, KEYEVENTF_EXTENDEDKEY,
     KEYEVENTF_KEYUP, KEYEVENTF_SCANCODE, SendInput, VIRTUAL_KEY,
@@ -12,49 +13,52 @@ use windows::Win32::UI::Input::KeyboardAndMouse::{
 
 const DW_EXTRA_INFO: usize = 123234;
 
-pub fn invoke(callback: Callback) {
-    debug!("Invoking {:?} as a keyboard.", callback);
+/// Sends keys to the BMS window using `SendInput`.
+pub struct SendInputBackend {
+    window_name: CString,
+}
 
-    if callback.combo_key_code != 0 {
-        invoke_keycode_with_modifiers(
-            callback.combo_modifiers,
-            callback.combo_key_code,
-            callback.readable_combo_key_code,
-        );
-        thread::sleep(Duration::from_millis(30));
+impl SendInputBackend {
+    pub fn new() -> Self {
+        Self {
+            window_name: CString::new("Falcon BMS").unwrap(),
+        }
     }
-    invoke_keycode_with_modifiers(
-        callback.modifiers,
-        callback.key_code,
-        callback.readable_key_code,
-    );
 }
 
-fn invoke_keycode_with_modifiers(modifiers: Vec<Modifier>, scan: u16, key: Key) {
-    let modifiers: Vec<_> = modifiers.iter().map(to_key).collect();
-
-    for modifier in modifiers.iter() {
-        self::raw(*modifier, Direction::Press, false);
+impl InputBackend for SendInputBackend {
+    fn focus_target(&mut self) -> InputResult<()> {
+        unsafe {
+            let window_handle =
+                user32::FindWindowA(std::ptr::null_mut(), self.window_name.as_ptr());
+            // probably SetForegroundWindow is enough, it was in the other server code.
+            if window_handle.is_null() {
+                return Err(InputError::TargetNotFound);
+            }
+            user32::SetForegroundWindow(window_handle);
+            user32::ShowWindow(window_handle, 9);
+        }
+        thread::sleep(Duration::from_millis(15));
+        Ok(())
     }
-    thread::sleep(Duration::from_millis(10));
-
-    self::raw(scan, Direction::Click, self::is_extended_key(key));
 
-    for modifier in modifiers.iter().rev() {
-        self::raw(*modifier, Direction::Release, false);
+    fn send(&mut self, strokes: &[KeyStroke]) -> InputResult<()> {
+        debug!("Sending {:?} as a keyboard.", strokes);
+        for stroke in strokes {
+            match stroke {
+                KeyStroke::Key {
+                    scan,
+                    direction,
+                    extended,
+                } => raw(*scan, *direction, *extended)?,
+                KeyStroke::Pause(duration) => thread::sleep(*duration),
+            }
+        }
+        Ok(())
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
-enum Direction {
-    Press,
-    Release,
-    Click,
-}
-
-type ScanCode = u16;
-
-fn raw(scan: u16, direction: Direction, extended: bool) {
+fn raw(scan: u16, direction: Direction, extended: bool) -> InputResult<()> {
     debug!("\x1b[35mraw(scan: {scan:?}, direction: {direction:?})\x1b[0m");
     let mut input = vec![];
 
@@ -78,12 +82,7 @@ fn raw(scan: u16, direction: Direction, extended: bool) {
         ));
     }
 
-    send_input(&input).expect("Failed to send input.");
-}
-
-fn is_extended_key(key: Key) -> bool {
-    // THE special snowflake keycode
-    key == Key::NumpadEnter
+    send_input(&input)
 }
 
 fn keybd_event(
@@ -130,26 +129,3 @@ fn send_input(input: &[INPUT]) -> InputResult<()> {
         ))
     }
 }
-
-pub type InputResult<T> = Result<T, InputError>;
-
-/// Error when simulating input
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub enum InputError {
-    /// There was an error with the protocol
-    Simulate(&'static str),
-    /// The input you want to simulate is invalid
-    /// This happens for example if you want to enter text that contains NULL
-    /// bytes (`\0`)
-    InvalidInput(&'static str),
-}
-
-fn to_key(m: &Modifier) -> u16 {
-    match m {
-        // we're going straight to the raw scancode for these, as per here:
-        // https://gist.github.com/arithex/3e953d1eb096afe58ce05ba6846493e4
-        Modifier::LSHIFT => 0x2A,
-        Modifier::LCONTROL => 0x1D,
-        Modifier::LALT => 0x38,
-    }
-}
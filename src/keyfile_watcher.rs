@@ -1,4 +1,4 @@
-use std::{sync::mpsc::Sender, thread, time::Duration};
+use std::{sync::mpsc::Sender, time::Duration};
 
 use log::{debug, error, trace};
 
@@ -9,7 +9,7 @@ use std::path::Path;
 use std::io::{Read, Seek};
 
 use crate::messages::Message;
-use crate::state::State;
+use crate::state::{SimInfo, State, lock};
 
 pub struct KeyfileWatcher {
     tx: Sender<Message>,
@@ -37,7 +37,10 @@ impl KeyfileWatcher {
                 break;
             }
 
-            if let Ok(string_data) = StringData::read() {
+            let string_data = StringData::read();
+            self.update_sim_info(string_data.as_ref().ok());
+
+            if let Ok(string_data) = string_data {
                 let key_file_path = &string_data[&StringId::KeyFile];
 
                 if !key_file_path.is_empty() {
@@ -82,10 +85,24 @@ impl KeyfileWatcher {
                 }
             }
 
-            thread::sleep(Duration::from_secs(5));
+            self.state.sleep_unless_cancelled(Duration::from_secs(5));
         }
     }
 
+    fn update_sim_info(&self, string_data: Option<&StringData>) {
+        let non_empty = |id: StringId| {
+            string_data
+                .map(|string_data| string_data[&id].clone())
+                .filter(|value| !value.is_empty())
+        };
+        let sim_info = SimInfo {
+            bms_running: string_data.is_some(),
+            theater: non_empty(StringId::ThrName),
+            aircraft: non_empty(StringId::AcName),
+        };
+        *lock(&self.state.sim_info) = sim_info;
+    }
+
     fn read_key_file_and_send_result(file_name: String, mut file: &File, tx: &Sender<Message>) {
         file.rewind().expect("Could not seek to beginning of file.");
         match falcon_key_file::parse(file_name, file) {
@@ -0,0 +1,48 @@
+use std::sync::{OnceLock, RwLock};
+
+use log::{Log, Metadata, Record};
+
+static LOGGER: OnceLock<ReloadableLogger> = OnceLock::new();
+
+/// An `env_logger` whose filters can be replaced while the server is running.
+struct ReloadableLogger {
+    inner: RwLock<env_logger::Logger>,
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.inner.read().unwrap().log(record)
+    }
+
+    fn flush(&self) {
+        self.inner.read().unwrap().flush()
+    }
+}
+
+fn build(filters: &str) -> env_logger::Logger {
+    env_logger::Builder::new().parse_filters(filters).build()
+}
+
+/// Installs the logger, `filters` are in the same format as `RUST_LOG`.
+pub fn init(filters: &str) {
+    let logger = LOGGER.get_or_init(|| ReloadableLogger {
+        inner: RwLock::new(build(filters)),
+    });
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(logger.inner.read().unwrap().filter());
+    }
+}
+
+/// Replaces the filters of the installed logger.
+pub fn set_filters(filters: &str) {
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+    let replacement = build(filters);
+    log::set_max_level(replacement.filter());
+    *logger.inner.write().unwrap() = replacement;
+}
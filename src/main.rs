@@ -1,84 +1,192 @@
+use crate::config_watcher::ConfigWatcher;
 use crate::keyfile_watcher::KeyfileWatcher;
+use crate::mdns_advertiser::MdnsAdvertiser;
+use std::process::ExitCode;
 use std::sync::Arc;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::enet_server::EnetServer;
+use crate::enet_server::{EnetServer, Outgoing};
 use crate::state::InnerState;
 use crate::state::State;
+use crate::state::lock;
+use crate::supervisor::Supervisor;
 use crate::udp_broadcast_listener::UdpBroadcastListener;
 use callbacks::CallbackSender;
+use cli::Cli;
 use config::Config;
+use error::Result;
 
-use env_logger::Env;
-use figment::Figment;
-use figment::providers::Format;
-use figment::providers::Serialized;
-use figment::providers::Toml;
+use clap::Parser;
 use log::debug;
+use log::error;
 use log::info;
 use messages::Message;
 
 mod callbacks;
+mod cli;
 mod config;
+mod config_watcher;
 mod enet_server;
+mod error;
+mod input_backend;
+mod key_strokes;
+#[cfg(windows)]
 mod keyboard_emulator;
 mod keyfile_watcher;
+mod logging;
+mod mdns_advertiser;
 mod messages;
 mod msgpack;
 mod state;
+mod supervisor;
 mod texture_reader;
 mod texture_stream;
 mod udp_broadcast_listener;
 
-fn main() {
-    let config: Config = Figment::new()
-        .merge(Serialized::defaults(Config::default()))
-        .merge(Toml::file("config.toml"))
-        .extract()
-        .expect("Failed to parse config.");
+/// The version of this server as described by git at build time.
+pub const VERSION: &str = match option_env!("VERGEN_GIT_DESCRIBE") {
+    Some(version) => version,
+    None => "Could not determine version!",
+};
+
+/// How long the subsystems get to stop once shutdown has been requested.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run() -> Result<()> {
+    let cli = Cli::parse();
+    let config = Config::load(&cli.figment())?;
+
+    if cli.print_config {
+        print!("{}", toml::to_string_pretty(&config)?);
+        return Ok(());
+    }
 
     let cancelled = Arc::new(AtomicBool::new(false));
     let cancel_handle = Arc::clone(&cancelled);
 
-    let env = Env::default().filter_or("LOG_LEVEL", &config.log_level);
-    env_logger::init_from_env(env);
+    logging::init(&config.log_level);
 
-    let version = option_env!("VERGEN_GIT_DESCRIBE").unwrap_or("Could not determine version!");
+    info!("falcon-bms-control server: {}", VERSION);
 
-    info!("falcon-bms-control server: {}", version);
+    let signal_handle = Arc::clone(&cancelled);
+    let result = ctrlc::set_handler(move || {
+        info!("Received stop signal");
+        signal_handle.store(true, Ordering::Relaxed);
+    });
+    if let Err(e) = result {
+        error!("Failed to set up stop signal handling: {}", e);
+    }
     debug!("Config is: {:?}", &config);
 
     // comms channels for threads
     let (tx, rx) = std::sync::mpsc::channel::<Message>();
+    let (packet_tx, packet_rx) = std::sync::mpsc::channel::<Outgoing>();
 
-    let state = State::new(InnerState::new(cancel_handle));
+    let state = State::new(InnerState::new(cancel_handle, config.streams.clone()));
+    let mut config_watcher = ConfigWatcher::new(cli, config.clone(), tx.clone(), state.clone());
 
     // all the stuff we're running concurrently
     let mut udp_broadcast_listener = UdpBroadcastListener::new(
         config.listen_address.clone(),
         config.broadcast_port,
+        config.server_name(),
+        config.listen_port,
+        state.clone(),
+    )?;
+
+    let mut mdns_advertiser = MdnsAdvertiser::new(
+        config.server_name(),
+        &config.listen_address,
+        config.listen_port,
+        config.mdns.loopback,
         state.clone(),
     );
 
     let enet_server = EnetServer::new(
         tx.clone(),
+        packet_tx.clone(),
+        packet_rx,
         config.listen_address,
         config.listen_port,
+        config.textures,
         state.clone(),
-    );
+    )?;
     let mut key_filewatcher = KeyfileWatcher::new(tx.clone(), state.clone());
-    let mut callback_sender = CallbackSender::new(rx, state.clone());
-
-    // run all of them
-    let h1 = thread::spawn(move || enet_server.run());
-    let h2 = thread::spawn(move || key_filewatcher.run());
-    let h3 = thread::spawn(move || callback_sender.run());
-    let h4 = thread::spawn(move || udp_broadcast_listener.run());
-
-    let _ = h1.join();
-    let _ = h2.join();
-    let _ = h3.join();
-    let _ = h4.join();
+    let mut callback_sender = CallbackSender::new(
+        rx,
+        packet_tx,
+        input_backend::create_input_backend(config.input.backend),
+        config.mappings,
+        state.clone(),
+    );
+
+    // run all of them, restarting whatever panics
+    let supervisor = Supervisor::new(state.clone());
+    let h1 = supervisor.spawn("enet server", move || enet_server.run());
+    let h2 = supervisor.spawn("key file watcher", move || {
+        key_filewatcher.run();
+        Ok(())
+    });
+    let h3 = supervisor.spawn("callback sender", move || {
+        callback_sender.run();
+        Ok(())
+    });
+    let h4 = supervisor.spawn("udp broadcast listener", move || {
+        udp_broadcast_listener.run();
+        Ok(())
+    });
+
+    let h5 = supervisor.spawn("config watcher", move || {
+        config_watcher.run();
+        Ok(())
+    });
+
+    let mut handles = vec![
+        ("enet server", h1),
+        ("key file watcher", h2),
+        ("callback sender", h3),
+        ("udp broadcast listener", h4),
+        ("config watcher", h5),
+    ];
+    if config.mdns.enabled {
+        let h6 = supervisor.spawn("mdns advertiser", move || {
+            mdns_advertiser.run();
+            Ok(())
+        });
+        handles.push(("mdns advertiser", h6));
+    }
+
+    while !cancelled.load(Ordering::Relaxed) && !handles.iter().all(|(_, h)| h.is_finished()) {
+        thread::sleep(Duration::from_millis(100));
+    }
     info!("Shutting down...");
+
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    while Instant::now() < deadline && !handles.iter().all(|(_, h)| h.is_finished()) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    for (name, handle) in handles {
+        if handle.is_finished() {
+            let _ = handle.join();
+        } else {
+            error!("The {} did not shut down in time", name);
+        }
+    }
+
+    match lock(&state.fatal_error).take() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
 }
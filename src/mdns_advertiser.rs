@@ -0,0 +1,156 @@
+use std::{net::IpAddr, time::Duration};
+
+use log::{debug, error, info};
+use mdns_sd::{IfKind, ServiceDaemon, ServiceInfo};
+
+use crate::{State, VERSION};
+
+/// The DNS-SD service type clients browse for.
+pub const SERVICE_TYPE: &str = "_falcon-bms._udp.local.";
+
+/// Advertises the server via multicast DNS, for networks where broadcast discovery does not get through.
+pub struct MdnsAdvertiser {
+    state: State,
+    name: String,
+    /// The address ENet listens on, unless it listens on all of them.
+    address: Option<IpAddr>,
+    port: u16,
+    loopback: bool,
+}
+
+impl MdnsAdvertiser {
+    pub fn new(name: String, address: &str, port: u16, loopback: bool, state: State) -> Self {
+        Self {
+            state,
+            name,
+            address: address
+                .parse::<IpAddr>()
+                .ok()
+                .filter(|ip| !ip.is_unspecified()),
+            port,
+            loopback,
+        }
+    }
+
+    pub fn run(&mut self) {
+        // mDNS is only a convenience, broadcast discovery and the server keep working without it.
+        let daemon = match ServiceDaemon::new() {
+            Ok(daemon) => daemon,
+            Err(e) => {
+                error!("Could not start mDNS, not advertising the server: {}", e);
+                return;
+            }
+        };
+        // ENet only accepts connections via IPv4, don't hand out addresses clients can't connect to.
+        let _ = daemon.disable_interface(IfKind::IPv6);
+        if self.loopback {
+            let _ = daemon.enable_interface(IfKind::LoopbackV4);
+        }
+
+        let service = match self.service_info() {
+            Ok(service) => service,
+            Err(e) => {
+                error!("Could not describe the server for mDNS: {}", e);
+                return;
+            }
+        };
+        let full_name = service.get_fullname().to_string();
+        match daemon.register(service) {
+            Ok(()) => info!("Advertising {} via mDNS", full_name),
+            Err(e) => error!("Could not advertise the server via mDNS: {}", e),
+        }
+
+        while !self.state.is_cancelled() {
+            self.state.sleep_unless_cancelled(Duration::from_secs(1));
+        }
+
+        // say goodbye, so browsers drop the server right away instead of when the record expires.
+        if let Ok(receiver) = daemon.unregister(&full_name) {
+            let _ = receiver.recv_timeout(Duration::from_secs(1));
+        }
+        if let Err(e) = daemon.shutdown() {
+            debug!("mDNS did not shut down cleanly: {}", e);
+        }
+    }
+
+    fn service_info(&self) -> Result<ServiceInfo, mdns_sd::Error> {
+        let host_name = format!("{}.local.", self.name.replace([' ', '.'], "-"));
+        let properties = [
+            ("version", VERSION.to_string()),
+            ("port", self.port.to_string()),
+        ];
+        let service = match self.address {
+            Some(address) => ServiceInfo::new(
+                SERVICE_TYPE,
+                &self.name,
+                &host_name,
+                address,
+                self.port,
+                &properties[..],
+            )?,
+            None => ServiceInfo::new(
+                SERVICE_TYPE,
+                &self.name,
+                &host_name,
+                (),
+                self.port,
+                &properties[..],
+            )?
+            .enable_addr_auto(),
+        };
+        Ok(service)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    };
+    use std::thread;
+    use std::time::Instant;
+
+    use mdns_sd::ServiceEvent;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::state::InnerState;
+
+    #[test]
+    fn can_be_found_by_a_browser_on_loopback() {
+        let cancellation_token = Arc::new(AtomicBool::new(false));
+        let state = State::new(InnerState::new(
+            Arc::clone(&cancellation_token),
+            Config::default().streams,
+        ));
+        let name = format!("test-{}", std::process::id());
+        let mut advertiser = MdnsAdvertiser::new(name.clone(), "0.0.0.0", 9022, true, state);
+        let advertising = thread::spawn(move || advertiser.run());
+
+        let browser = ServiceDaemon::new().unwrap();
+        browser.enable_interface(IfKind::LoopbackV4).unwrap();
+        let events = browser.browse(SERVICE_TYPE).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let resolved = loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match events.recv_timeout(timeout) {
+                Ok(ServiceEvent::ServiceResolved(info))
+                    if info.get_fullname() == format!("{}.{}", name, SERVICE_TYPE) =>
+                {
+                    break info;
+                }
+                Ok(_) => {}
+                Err(e) => panic!("{} was not resolved: {}", name, e),
+            }
+        };
+
+        assert_eq!(resolved.get_port(), 9022);
+        assert_eq!(resolved.get_property_val_str("version"), Some(VERSION));
+        assert_eq!(resolved.get_property_val_str("port"), Some("9022"));
+
+        cancellation_token.store(true, Ordering::Relaxed);
+        advertising.join().unwrap();
+        let _ = browser.shutdown();
+    }
+}
@@ -1,16 +1,29 @@
+use crate::state::ClientId;
 use falcon_key_file::FalconKeyfile;
 
+use crate::config::Mappings;
 use crate::msgpack;
 
 #[derive(Debug)]
 pub enum Message {
     /// Sent when we received a callback
     EnetReceived {
+        peer_id: ClientId,
         message: msgpack::ProtocolMessage,
     },
 
+    /// Sent when a peer went away, to release whatever it was holding down
+    PeerDisconnected {
+        peer_id: ClientId,
+    },
+
     // Sent whenever a new kezfile has been read
     KeyfileRead {
         key_file: FalconKeyfile,
     },
+
+    /// Sent when the mappings in the config file were changed
+    MappingsChanged {
+        mappings: Mappings,
+    },
 }
@@ -1,32 +1,90 @@
 use serde::Deserialize;
 use serde::Serialize;
 
+/// The protocol version spoken by this server, bump this on breaking changes.
+pub const PROTOCOL_VERSION: u16 = 4;
+
+/// The oldest client protocol version this server still talks to.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Clients that do not send a version in their hello predate the handshake.
+const LEGACY_PROTOCOL_VERSION: u16 = 1;
+
+/// All message types a client may send to this server, as they appear on the wire.
+pub const SUPPORTED_MESSAGES: &[&str] = &[
+    "hello",
+    "icp-pressed",
+    "icp-released",
+    "osb-pressed",
+    "osb-released",
+    "streamed-texture",
+    "request-keyframe",
+    "invoke-callback",
+    "list-callbacks",
+    "get-health",
+];
+
+/// All encodings a client may request for streamed textures.
+pub const SUPPORTED_ENCODINGS: &[&str] = &["jpeg", "png", "webp", "webp-lossless", "qoi"];
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ProtocolMessage {
     #[serde(rename = "hello")]
-    Hello {},
+    Hello {
+        protocol_version: Option<u16>,
+    },
+    /// The answer to a discovery `Hello`, everything a client needs to list and connect to the server.
     #[serde(rename = "ack")]
-    Ack {},
+    Ack {
+        name: String,
+        server_version: String,
+        /// The ENet port to connect to.
+        port: u16,
+        /// The IPv4 address to connect to, or none to use the one this reply came from.
+        /// Connections are IPv4 only, so replies sent over IPv6 always carry one, unless
+        /// the server has no IPv4 address at all.
+        address: Option<String>,
+        bms_running: bool,
+        theater: Option<String>,
+        aircraft: Option<String>,
+        clients: usize,
+    },
+    #[serde(rename = "welcome")]
+    Welcome {
+        protocol_version: u16,
+        server_version: String,
+        messages: Vec<String>,
+        textures: Vec<String>,
+        encodings: Vec<String>,
+    },
+    #[serde(rename = "rejected")]
+    Rejected {
+        reason: String,
+    },
     #[serde(rename = "icp-pressed")]
     IcpButtonPressed {
         icp: Option<String>,
         button: String,
+        correlation_id: Option<u32>,
     },
     #[serde(rename = "icp-released")]
     IcpButtonReleased {
         icp: Option<String>,
         button: String,
+        correlation_id: Option<u32>,
     },
     #[serde(rename = "osb-pressed")]
     OsbButtonPressed {
         mfd: String,
         osb: String,
+        correlation_id: Option<u32>,
     },
     #[serde(rename = "osb-released")]
     OsbButtonReleased {
         mfd: String,
         osb: String,
+        correlation_id: Option<u32>,
     },
     #[serde(rename = "streamed-texture")]
     StreamedTextureRequest {
@@ -34,6 +92,68 @@ pub enum ProtocolMessage {
         command: Command,
         refresh_rate: Option<u16>,
         quality: Option<u16>,
+        /// Without one, frames are sent as bare JPEG like before there was a choice.
+        encoding: Option<Encoding>,
+        /// Only used for JPEG, defaults to 4:2:0.
+        subsampling: Option<ChromaSubsampling>,
+        /// Scales frames to this size, how is up to `resize`.
+        size: Option<Size>,
+        resize: Option<ResizeMode>,
+        /// Only streams this part of the texture, in texture pixels. Applied before scaling.
+        crop: Option<Rect>,
+        /// Only send the tiles that changed between keyframes, needs an `encoding`.
+        delta: Option<DeltaRequest>,
+        /// Lets the server lower refresh rate and quality while the link is struggling.
+        adaptive: Option<AdaptiveRequest>,
+        correlation_id: Option<u32>,
+    },
+    /// Asks for the next frame of a stream to be a keyframe, e.g. after losing a delta frame.
+    #[serde(rename = "request-keyframe")]
+    RequestKeyframe {
+        identifier: String,
+        correlation_id: Option<u32>,
+    },
+    /// Invokes any callback from the loaded key file by name, e.g. `SimMasterArm`.
+    #[serde(rename = "invoke-callback")]
+    InvokeCallback {
+        callback: String,
+        action: Option<CallbackAction>,
+        correlation_id: Option<u32>,
+    },
+    #[serde(rename = "list-callbacks")]
+    ListCallbacks {
+        correlation_id: Option<u32>,
+    },
+    /// The callbacks bound to keys in the loaded key file. Sent as the reply to
+    /// `ListCallbacks` and pushed to everybody whenever the key file changes.
+    #[serde(rename = "callbacks")]
+    Callbacks {
+        correlation_id: Option<u32>,
+        callbacks: Vec<CallbackInfo>,
+    },
+    #[serde(rename = "get-health")]
+    GetHealth {
+        correlation_id: Option<u32>,
+    },
+    /// How each of the server's subsystems is doing.
+    #[serde(rename = "health")]
+    Health {
+        correlation_id: Option<u32>,
+        subsystems: Vec<SubsystemHealth>,
+    },
+    /// Sent back for a request carrying a `correlation_id` once it has been handled.
+    #[serde(rename = "ok")]
+    Acknowledged {
+        correlation_id: Option<u32>,
+    },
+    /// Sent back whenever a request failed, or something the client relies on broke.
+    #[serde(rename = "error")]
+    Error {
+        correlation_id: Option<u32>,
+        code: ErrorCode,
+        message: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        suggestions: Vec<String>,
     },
     Unknown,
 }
@@ -45,3 +165,204 @@ pub enum Command {
     #[serde(rename = "stop")]
     Stop,
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CallbackInfo {
+    pub name: String,
+    pub key: String,
+    pub modifiers: Vec<String>,
+    pub combo_key: Option<String>,
+    pub combo_modifiers: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubsystemHealth {
+    pub name: String,
+    pub status: SubsystemStatus,
+    pub restarts: u32,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SubsystemStatus {
+    Running,
+    /// Failed and waiting to be restarted.
+    Restarting,
+    Stopped,
+}
+
+/// How streamed texture frames are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Encoding {
+    Jpeg,
+    Png,
+    /// Lossy WebP, using the requested quality.
+    Webp,
+    WebpLossless,
+    Qoi,
+}
+
+impl Encoding {
+    /// Whether the requested quality makes a difference.
+    pub fn is_lossy(self) -> bool {
+        matches!(self, Encoding::Jpeg | Encoding::Webp)
+    }
+}
+
+/// Chroma subsampling for JPEG, 4:4:4 keeps thin colored lines sharp at the cost of size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum ChromaSubsampling {
+    #[serde(rename = "4:4:4")]
+    None,
+    #[serde(rename = "4:2:2")]
+    Half,
+    #[serde(rename = "4:2:0")]
+    #[default]
+    Quarter,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Size {
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// How a texture is scaled to the requested size, both keep the aspect ratio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResizeMode {
+    /// Scale down until it fits, frames may be smaller than requested in one dimension.
+    #[default]
+    Fit,
+    /// Scale until it covers the size and cut off what sticks out. Sizes larger than the
+    /// texture are shrunk to fit it first, so frames may be smaller than requested.
+    Fill,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeltaRequest {
+    /// The width and height of a tile in pixels.
+    pub tile_size: Option<u32>,
+    /// Send a keyframe after this many delta frames.
+    pub keyframe_interval: Option<u32>,
+}
+
+/// How far the server may adapt a stream to the link. The requested refresh rate
+/// and quality are where it starts and, unless given here, the maximum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdaptiveRequest {
+    pub min_refresh_rate: Option<u16>,
+    pub max_refresh_rate: Option<u16>,
+    pub min_quality: Option<u16>,
+    pub max_quality: Option<u16>,
+}
+
+/// A part of a delta frame, encoded like a whole frame would be.
+#[derive(Debug, Serialize)]
+pub struct Tile<'a> {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    #[serde(with = "serde_bytes")]
+    pub data: &'a [u8],
+}
+
+/// A streamed texture frame as sent on the texture's channel, to clients that chose an encoding.
+#[derive(Debug, Serialize)]
+pub struct Frame<'a> {
+    pub identifier: &'a str,
+    /// Counts the frames sent to this client, a gap means frames got lost on the way.
+    pub sequence: u32,
+    /// When the texture was read, in milliseconds since the unix epoch.
+    pub captured_at: u64,
+    pub width: u32,
+    pub height: u32,
+    pub encoding: Encoding,
+    /// None for lossless encodings.
+    pub quality: Option<u16>,
+    /// How many frames were not sent since the previous one, because the texture did not change.
+    pub skipped: u32,
+    /// Whether `data` is the whole image. If not, `data` is empty and only the tiles
+    /// that changed since the previous frame are in `tiles`.
+    pub keyframe: bool,
+    #[serde(with = "serde_bytes")]
+    pub data: &'a [u8],
+    pub tiles: Vec<Tile<'a>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CallbackAction {
+    /// Press and release right away.
+    #[default]
+    Click,
+    /// Press and hold until released.
+    Press,
+    Release,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCode {
+    /// The message could not be parsed.
+    InvalidMessage,
+    /// The mfd, button or texture identifier is not known to the server.
+    UnknownIdentifier,
+    /// The peer is not streaming the texture the request is about.
+    NotStreaming,
+    /// A value in the request is outside of what the server accepts.
+    InvalidValue,
+    /// The callback is not in the loaded key file.
+    UnknownCallback,
+    /// The callback is in the key file, but not bound to a key.
+    CallbackNotBound,
+    /// No key file has been read from BMS yet.
+    KeyFileNotLoaded,
+    /// BMS is not running or not exporting shared memory.
+    BmsNotRunning,
+    /// Reading or encoding a texture failed.
+    TextureReadFailed,
+    /// The keys could not be sent to BMS.
+    InputFailed,
+}
+
+impl ProtocolMessage {
+    pub fn error(correlation_id: Option<u32>, code: ErrorCode, message: impl Into<String>) -> Self {
+        ProtocolMessage::Error {
+            correlation_id,
+            code,
+            message: message.into(),
+            suggestions: vec![],
+        }
+    }
+}
+
+/// Checks whether a client announcing `protocol_version` can talk to this server.
+/// Returns the reason for rejecting it if not.
+pub fn check_compatibility(protocol_version: Option<u16>) -> Result<(), String> {
+    let version = protocol_version.unwrap_or(LEGACY_PROTOCOL_VERSION);
+    if version < MIN_PROTOCOL_VERSION {
+        Err(format!(
+            "Client protocol version {} is too old, this server requires at least {}. Please update the app.",
+            version, MIN_PROTOCOL_VERSION
+        ))
+    } else if version > PROTOCOL_VERSION {
+        Err(format!(
+            "Client protocol version {} is newer than this server's ({}). Please update the server.",
+            version, PROTOCOL_VERSION
+        ))
+    } else {
+        Ok(())
+    }
+}
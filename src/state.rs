@@ -2,11 +2,17 @@ use std::ops::Deref;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 
-use std::sync::Mutex;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
-use enet::PeerID;
+use crate::config::StreamDefaults;
+use crate::error::Error;
+use crate::msgpack::{SubsystemHealth, SubsystemStatus};
+use crate::texture_reader::TextureId;
+use crate::texture_stream::{KeyframeRequests, Subscribers};
 
 #[derive(Clone)]
 pub struct State {
@@ -14,14 +20,81 @@ pub struct State {
 }
 
 pub struct InnerState {
-    pub streams_running: Arc<Mutex<HashMap<StreamKey, Arc<AtomicBool>>>>,
+    pub streams_running: Arc<Mutex<HashMap<TextureId, RunningStream>>>,
     pub cancellation_token: Arc<AtomicBool>,
+    pub connected_peers: Mutex<HashSet<ClientId>>,
+    pub health: Mutex<BTreeMap<&'static str, SubsystemHealth>>,
+    /// Set when a subsystem failed in a way that takes the server down.
+    pub fatal_error: Mutex<Option<Error>>,
+    /// Stream options for clients that do not choose their own, can change on config reload.
+    pub stream_defaults: Mutex<StreamDefaults>,
+    pub sim_info: Mutex<SimInfo>,
+    /// How well each peer's connection is doing, as last measured by ENet.
+    pub link_stats: LinkStats,
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
-pub struct StreamKey {
-    pub peer_id: PeerID,
-    pub identifier: String,
+pub type LinkStats = Arc<Mutex<HashMap<ClientId, LinkQuality>>>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LinkQuality {
+    pub round_trip_time: Duration,
+    /// The fraction of packets lost, from 0 to 1. ENet only counts reliable packets, its
+    /// pings and the control messages, not the frames sent unreliably. So this is an
+    /// estimate of what happens to frames, which a congested link also shows in the round
+    /// trip time.
+    pub packet_loss: f32,
+}
+
+/// How the server tells its clients apart. ENet's peer ids only exist inside the ENet
+/// server, which knows the peer behind each client, since nothing but ENet can make those.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ClientId(pub u64);
+
+/// What is known about the running simulation, as of the last look at its shared memory.
+#[derive(Debug, Clone, Default)]
+pub struct SimInfo {
+    pub bms_running: bool,
+    pub theater: Option<String>,
+    pub aircraft: Option<String>,
+}
+
+/// A texture being captured, shared by all peers subscribed to it.
+pub struct RunningStream {
+    pub cancellation_token: Arc<AtomicBool>,
+    pub subscribers: Subscribers,
+    pub keyframe_requests: KeyframeRequests,
+    pub thread: Option<JoinHandle<()>>,
+}
+
+impl RunningStream {
+    pub fn new() -> Self {
+        Self {
+            cancellation_token: Arc::new(AtomicBool::new(false)),
+            subscribers: Arc::new(Mutex::new(HashMap::new())),
+            keyframe_requests: Arc::new(Mutex::new(HashSet::new())),
+            thread: None,
+        }
+    }
+
+    /// Whether the capture thread ended, e.g. because it panicked.
+    pub fn is_finished(&self) -> bool {
+        self.thread
+            .as_ref()
+            .is_some_and(|thread| thread.is_finished())
+    }
+
+    /// Removes the peer and cancels the stream if nobody is left watching it.
+    /// Returns whether the stream is still running.
+    fn remove_subscriber(&self, id: ClientId) -> bool {
+        let mut subscribers = lock(&self.subscribers);
+        subscribers.remove(&id);
+        if subscribers.is_empty() {
+            self.cancellation_token.store(true, Ordering::Relaxed);
+            false
+        } else {
+            true
+        }
+    }
 }
 
 impl State {
@@ -31,24 +104,110 @@ impl State {
         }
     }
 
-    pub fn cancel_all_streams(&self, id: PeerID) {
-        let streams = self.streams_running.lock().unwrap();
-        let keys_to_cancel = streams.keys().filter(|key| key.peer_id == id);
-        for key in keys_to_cancel {
-            streams.get(key).unwrap().store(true, Ordering::Relaxed);
+    pub fn cancel_all_streams(&self, id: ClientId) {
+        let mut streams = lock(&self.streams_running);
+        streams.retain(|_, stream| stream.remove_subscriber(id));
+    }
+
+    /// Stops every stream and waits for their threads to finish.
+    pub fn stop_all_streams(&self) {
+        let streams: Vec<RunningStream> = lock(&self.streams_running)
+            .drain()
+            .map(|(_, stream)| stream)
+            .collect();
+        for stream in streams.iter() {
+            stream.cancellation_token.store(true, Ordering::Relaxed);
+        }
+        for stream in streams {
+            if let Some(thread) = stream.thread {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation_token.load(Ordering::Relaxed)
+    }
+
+    /// Sleeps for `duration`, but wakes up early when the server is shutting down.
+    pub fn sleep_unless_cancelled(&self, duration: Duration) {
+        let deadline = Instant::now() + duration;
+        while !self.is_cancelled() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            thread::sleep((deadline - now).min(Duration::from_millis(100)));
+        }
+    }
+
+    /// Makes the next frame the peer gets of the texture a keyframe.
+    /// Returns false if the peer is not streaming it.
+    pub fn request_keyframe(&self, texture_id: TextureId, id: ClientId) -> bool {
+        let streams = lock(&self.streams_running);
+        match streams.get(&texture_id) {
+            Some(stream) if lock(&stream.subscribers).contains_key(&id) => {
+                lock(&stream.keyframe_requests).insert(id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn cancel_stream(&self, texture_id: TextureId, id: ClientId) {
+        let mut streams = lock(&self.streams_running);
+        if let Some(stream) = streams.get(&texture_id)
+            && !stream.remove_subscriber(id)
+        {
+            streams.remove(&texture_id);
+        }
+    }
+
+    /// Remembers the first fatal error and shuts everything down.
+    pub fn fail(&self, error: Error) {
+        lock(&self.fatal_error).get_or_insert(error);
+        self.cancellation_token.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_health(&self, name: &'static str, status: SubsystemStatus, error: Option<String>) {
+        let mut health = lock(&self.health);
+        let entry = health.entry(name).or_insert_with(|| SubsystemHealth {
+            name: name.to_string(),
+            status,
+            restarts: 0,
+            last_error: None,
+        });
+        if status == SubsystemStatus::Restarting {
+            entry.restarts += 1;
+        }
+        entry.status = status;
+        if error.is_some() {
+            entry.last_error = error;
         }
     }
 }
 
 impl InnerState {
-    pub fn new(cancellation_token: Arc<AtomicBool>) -> Self {
+    pub fn new(cancellation_token: Arc<AtomicBool>, stream_defaults: StreamDefaults) -> Self {
         Self {
             streams_running: Arc::new(Mutex::new(HashMap::new())),
             cancellation_token,
+            connected_peers: Mutex::new(HashSet::new()),
+            health: Mutex::new(BTreeMap::new()),
+            fatal_error: Mutex::new(None),
+            stream_defaults: Mutex::new(stream_defaults),
+            sim_info: Mutex::new(SimInfo::default()),
+            link_stats: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 }
 
+/// Locks `mutex` even when a thread panicked while holding it. The supervisor restarts
+/// subsystems that panic, and they would only panic again on a poisoned lock.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 impl Deref for State {
     type Target = InnerState;
 
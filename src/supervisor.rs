@@ -0,0 +1,96 @@
+use std::{
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use log::{error, info, warn};
+
+use crate::error::{Error, Result};
+use crate::msgpack::SubsystemStatus;
+use crate::state::State;
+
+/// The first restart happens after this, doubling with every failure in a row.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// A subsystem running at least this long before failing starts over with the initial backoff.
+const HEALTHY_RUN: Duration = Duration::from_secs(60);
+
+/// Runs each subsystem on its own thread and restarts it when it panics,
+/// keeping track of its health in the shared state. A subsystem returning an error
+/// can't be helped by restarting, so that shuts the whole server down.
+pub struct Supervisor {
+    state: State,
+}
+
+impl Supervisor {
+    pub fn new(state: State) -> Self {
+        Self { state }
+    }
+
+    pub fn spawn<F>(&self, name: &'static str, mut run: F) -> JoinHandle<()>
+    where
+        F: FnMut() -> Result<()> + Send + 'static,
+    {
+        let state = self.state.clone();
+        state.set_health(name, SubsystemStatus::Running, None);
+
+        thread::spawn(move || {
+            let mut backoff = INITIAL_BACKOFF;
+            loop {
+                let started = Instant::now();
+                let result = panic::catch_unwind(AssertUnwindSafe(&mut run));
+
+                match result {
+                    Ok(Ok(())) => {
+                        info!("The {} stopped", name);
+                        state.set_health(name, SubsystemStatus::Stopped, None);
+                        break;
+                    }
+                    Ok(Err(e)) => {
+                        error!("The {} failed: {}", name, e);
+                        state.set_health(name, SubsystemStatus::Stopped, Some(e.to_string()));
+                        state.fail(Error::Subsystem {
+                            name,
+                            source: Box::new(e),
+                        });
+                        break;
+                    }
+                    Err(payload) => {
+                        let message = panic_message(payload);
+                        error!("The {} panicked: {}", name, message);
+                        if state.is_cancelled() {
+                            state.set_health(name, SubsystemStatus::Stopped, Some(message));
+                            break;
+                        }
+
+                        if started.elapsed() > HEALTHY_RUN {
+                            backoff = INITIAL_BACKOFF;
+                        }
+                        warn!("Restarting the {} in {:?}", name, backoff);
+                        state.set_health(name, SubsystemStatus::Restarting, Some(message));
+                        state.sleep_unless_cancelled(backoff);
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+
+                        if state.is_cancelled() {
+                            state.set_health(name, SubsystemStatus::Stopped, None);
+                            break;
+                        }
+                        state.set_health(name, SubsystemStatus::Running, None);
+                    }
+                }
+            }
+        })
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
@@ -1,9 +1,18 @@
-use bms_sm::{FlightData2, RttTextures};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use bms_sm::{FlightData2, RttArea, RttTextures, StringData};
 use image::RgbImage;
-use log::error;
+use log::{debug, error, info};
+
+use crate::config::{TextureConfig, TextureSourceKind};
 
 /// an identifier and the channel to send on.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(u8)]
 pub enum TextureId {
     LeftMfd = 1,
@@ -25,39 +34,210 @@ impl From<&str> for TextureId {
     }
 }
 
-pub fn rtt_texture_read(texture_id: TextureId) -> Result<RgbImage, std::io::Error> {
-    let tx_result = RttTextures::read();
-    let fd_result = FlightData2::new();
+impl TextureId {
+    /// All textures that can be streamed, without `Unknown`.
+    pub const ALL: [TextureId; 4] = [
+        TextureId::LeftMfd,
+        TextureId::RightMfd,
+        TextureId::Ded,
+        TextureId::Rwr,
+    ];
 
-    if let (Ok(textures), Ok(flight_data)) = (tx_result, fd_result) {
-        let flight_data2 = flight_data.read();
+    /// The identifier clients use to request this texture.
+    pub fn identifier(&self) -> &'static str {
+        match self {
+            TextureId::LeftMfd => "f16/left-mfd",
+            TextureId::RightMfd => "f16/right-mfd",
+            TextureId::Ded => "f16/ded",
+            TextureId::Rwr => "f16/rwr",
+            TextureId::Unknown => "unknown",
+        }
+    }
+}
 
-        match texture_id {
-            TextureId::LeftMfd => {
-                let c = flight_data2.get_rtt_area(bms_sm::RttArea::MfdLeft);
-                Ok(textures.get_image(c.left, c.top, c.right, c.bottom))
-            }
-            TextureId::RightMfd => {
-                let c = flight_data2.get_rtt_area(bms_sm::RttArea::MfdRight);
-                Ok(textures.get_image(c.left, c.top, c.right, c.bottom))
-            }
-            TextureId::Ded => {
-                let c = flight_data2.get_rtt_area(bms_sm::RttArea::Ded);
-                Ok(textures.get_image(c.left, c.top, c.right, c.bottom))
-            }
-            TextureId::Rwr => {
-                let c = flight_data2.get_rtt_area(bms_sm::RttArea::Rwr);
-                Ok(textures.get_image(c.left, c.top, c.right, c.bottom))
-            }
+/// Image file extensions the file backed texture source picks up.
+const IMAGE_EXTENSIONS: &[&str] = &["jpeg", "jpg", "png"];
+
+/// Where streamed textures come from.
+pub trait TextureSource {
+    fn read(&mut self, texture_id: TextureId) -> Result<RgbImage, std::io::Error>;
+}
+
+/// Creates the texture source configured, on the thread that is going to read from it.
+pub fn create_texture_source(config: &TextureConfig) -> Box<dyn TextureSource> {
+    match config.source {
+        TextureSourceKind::Bms => Box::new(RttTextureReader::new()),
+        TextureSourceKind::Files => Box::new(FileTextureSource::new(&config.directory)),
+    }
+}
+
+/// How often a mapped reader looks for BMS having exited, it's not worth doing every frame.
+const EXIT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reads RTT textures from BMS shared memory, keeping it mapped between frames.
+pub struct RttTextureReader {
+    mapped: Option<MappedSharedMemory>,
+    checked_at: Instant,
+}
+
+struct MappedSharedMemory {
+    textures: RttTextures,
+    flight_data: FlightData2,
+}
+
+impl RttTextureReader {
+    pub fn new() -> Self {
+        Self {
+            mapped: None,
+            checked_at: Instant::now(),
+        }
+    }
+
+    fn map() -> Result<MappedSharedMemory, std::io::Error> {
+        if let (Ok(textures), Ok(flight_data)) = (RttTextures::read(), FlightData2::new()) {
+            debug!("Mapped BMS shared memory");
+            Ok(MappedSharedMemory {
+                textures,
+                flight_data,
+            })
+        } else {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "BMS is not running or exporting.",
+            ))
+        }
+    }
+
+    /// Holding on to the mapping keeps BMS' shared memory alive after it exits, with the last
+    /// frame in it. The string data is only mapped while it is read, so it going away means
+    /// BMS exited, or is being restarted.
+    fn bms_exited() -> bool {
+        StringData::read().is_err()
+    }
+}
+
+impl TextureSource for RttTextureReader {
+    fn read(&mut self, texture_id: TextureId) -> Result<RgbImage, std::io::Error> {
+        let area = match texture_id {
+            TextureId::LeftMfd => RttArea::MfdLeft,
+            TextureId::RightMfd => RttArea::MfdRight,
+            TextureId::Ded => RttArea::Ded,
+            TextureId::Rwr => RttArea::Rwr,
             _ => {
                 error!("Unhandled texture id: {:?}", texture_id);
-                Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, ""))
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, ""));
+            }
+        };
+
+        if self.mapped.is_some() && self.checked_at.elapsed() >= EXIT_CHECK_INTERVAL {
+            self.checked_at = Instant::now();
+            if Self::bms_exited() {
+                debug!("BMS exited, unmapping its shared memory");
+                self.mapped = None;
             }
         }
-    } else {
-        Err(std::io::Error::new(
-            std::io::ErrorKind::NotConnected,
-            "BMS is not running or exporting.",
-        ))
+
+        let mapped = match self.mapped {
+            Some(ref mapped) => mapped,
+            None => self.mapped.insert(Self::map()?),
+        };
+
+        let c = mapped.flight_data.read().get_rtt_area(area);
+        Ok(mapped.textures.get_image(c.left, c.top, c.right, c.bottom))
+    }
+}
+
+/// Serves still images or image sequences from a directory, for working without BMS.
+/// `f16/left-mfd` is served from `left-mfd.jpeg` (or `.jpg`, `.png`), or from all images
+/// in a `left-mfd` directory in file name order, one per frame.
+pub struct FileTextureSource {
+    directory: PathBuf,
+    sequences: HashMap<TextureId, ImageSequence>,
+}
+
+struct ImageSequence {
+    images: Vec<RgbImage>,
+    next: usize,
+}
+
+impl FileTextureSource {
+    pub fn new(directory: impl AsRef<Path>) -> Self {
+        Self {
+            directory: directory.as_ref().to_path_buf(),
+            sequences: HashMap::new(),
+        }
     }
+
+    fn load(&self, texture_id: TextureId) -> Result<ImageSequence, std::io::Error> {
+        let name = texture_id
+            .identifier()
+            .rsplit('/')
+            .next()
+            .unwrap_or_default();
+        let path = self.directory.join(name);
+
+        let paths = if path.is_dir() {
+            let mut paths: Vec<PathBuf> = fs::read_dir(&path)?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|p| is_image(p))
+                .collect();
+            paths.sort();
+            paths
+        } else {
+            IMAGE_EXTENSIONS
+                .iter()
+                .map(|extension| path.with_extension(extension))
+                .filter(|p| p.is_file())
+                .take(1)
+                .collect()
+        };
+
+        if paths.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!(
+                    "No images for {:?} in {}",
+                    texture_id,
+                    self.directory.display()
+                ),
+            ));
+        }
+
+        let images = paths
+            .iter()
+            .map(|p| {
+                image::open(p)
+                    .map(|image| image.to_rgb8())
+                    .map_err(std::io::Error::other)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        info!(
+            "Serving {:?} from {} image(s) in {}",
+            texture_id,
+            images.len(),
+            path.display()
+        );
+        Ok(ImageSequence { images, next: 0 })
+    }
+}
+
+impl TextureSource for FileTextureSource {
+    fn read(&mut self, texture_id: TextureId) -> Result<RgbImage, std::io::Error> {
+        if !self.sequences.contains_key(&texture_id) {
+            let sequence = self.load(texture_id)?;
+            self.sequences.insert(texture_id, sequence);
+        }
+
+        let sequence = self.sequences.get_mut(&texture_id).unwrap();
+        let image = sequence.images[sequence.next].clone();
+        sequence.next = (sequence.next + 1) % sequence.images.len();
+        Ok(image)
+    }
+}
+
+fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| IMAGE_EXTENSIONS.contains(&e.to_lowercase().as_str()))
 }
@@ -1,119 +1,1306 @@
-use crate::texture_reader;
+use image::{
+    ImageFormat, RgbImage,
+    imageops::{self, FilterType},
+};
 use log::{debug, error};
+use seahash::SeaHasher;
 use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    hash::Hasher,
+    io::Cursor,
+    ops::RangeInclusive,
     sync::{
-        Arc,
+        Arc, Mutex,
         atomic::{AtomicBool, Ordering},
         mpsc::Sender,
     },
     thread,
-    time::Duration,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use turbojpeg::Image;
-use turbojpeg::PixelFormat;
+use turbojpeg::{PixelFormat, Subsamp};
+
+use crate::{
+    config::{self, QUALITIES, REFRESH_RATES, StreamDefaults, TextureConfig},
+    enet_server::{self, Delivery, Outgoing, PacketData},
+    msgpack::{
+        AdaptiveRequest, ChromaSubsampling, DeltaRequest, Encoding, ErrorCode, Frame,
+        ProtocolMessage, Rect, ResizeMode, Size, Tile,
+    },
+    state::{ClientId, LinkStats, lock},
+    texture_reader::{self, TextureId},
+};
 
-use crate::{enet_server::PacketData, state::StreamKey, texture_reader::TextureId};
+/// Every peer streaming a texture and the options it asked for.
+pub type Subscribers = Arc<Mutex<HashMap<ClientId, StreamOptions>>>;
 
+/// Peers that asked for their next frame to be a keyframe.
+pub type KeyframeRequests = Arc<Mutex<HashSet<ClientId>>>;
+
+const DEFAULT_TILE_SIZE: u32 = 64;
+const MIN_TILE_SIZE: u32 = 16;
+const DEFAULT_KEYFRAME_INTERVAL: u32 = 60;
+/// The largest width and height a client may ask a texture to be scaled to.
+const MAX_SIZE: u32 = 4096;
+
+/// How often adaptive streams are adjusted to the link.
+const ADAPT_INTERVAL: Duration = Duration::from_secs(2);
+const QUALITY_STEP: u16 = 10;
+const DEFAULT_MIN_REFRESH_RATE: u16 = 5;
+const DEFAULT_MIN_QUALITY: u16 = 30;
+/// Links worse than this get less, links better than the healthy ones get more again.
+const DEGRADED_PACKET_LOSS: f32 = 0.05;
+const DEGRADED_ROUND_TRIP_TIME: Duration = Duration::from_millis(150);
+const HEALTHY_PACKET_LOSS: f32 = 0.01;
+const HEALTHY_ROUND_TRIP_TIME: Duration = Duration::from_millis(60);
+
+/// Captures a single texture and fans the encoded frames out to all subscribed peers,
+/// so that each frame is read once and encoded once per distinct set of `EncodeSettings`.
 pub struct TextureStream {
     cancellation_token: Arc<AtomicBool>,
-    stream_key: StreamKey,
-    stream_options: StreamOptions,
-    tx: Sender<PacketData>,
-    last_hash: Option<u64>,
+    texture_id: TextureId,
+    subscribers: Subscribers,
+    keyframe_requests: KeyframeRequests,
+    link_stats: LinkStats,
+    texture_config: TextureConfig,
+    tx: Sender<Outgoing>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct StreamOptions {
     pub refresh_rate: u16,
     pub quality: u16,
+    /// None for clients that expect bare JPEG frames.
+    pub encoding: Option<Encoding>,
+    pub subsampling: ChromaSubsampling,
+    pub transform: Transform,
+    /// Only for clients that chose an encoding, bare JPEG has no room for tiles.
+    pub delta: Option<DeltaOptions>,
+    pub adaptive: Option<AdaptiveBounds>,
+}
+
+/// The range adaptive streams move their refresh rate and quality in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdaptiveBounds {
+    pub min_refresh_rate: u16,
+    pub max_refresh_rate: u16,
+    pub min_quality: u16,
+    pub max_quality: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeltaOptions {
+    pub tile_size: u32,
+    pub keyframe_interval: u32,
+}
+
+/// The part of the `StreamOptions` that determines the encoded bytes,
+/// peers with equal settings share the encoded frame.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct EncodeSettings {
+    /// Clients that did not choose an encoding get JPEG, and share it with those that did.
+    pub encoding: Encoding,
+    pub quality: u16,
+    pub subsampling: ChromaSubsampling,
+    pub transform: Transform,
+}
+
+/// Cropping and scaling applied to a texture before it is encoded.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Transform {
+    pub crop: Option<Rect>,
+    pub size: Option<Size>,
+    pub resize: ResizeMode,
+}
+
+/// A frame cropped and scaled for one set of `EncodeSettings`, shared by all subscribers
+/// using them. It is only encoded as a whole or in tiles as far as subscribers need it.
+struct PreparedFrame<'a> {
+    image: Cow<'a, RgbImage>,
+    settings: EncodeSettings,
+    /// None until encoded, then None again if encoding failed.
+    full: Option<Option<Vec<u8>>>,
+    hash: Option<u64>,
+    grids: HashMap<u32, TileGrid>,
+    tiles: HashMap<(u32, usize), Option<Vec<u8>>>,
+}
+
+/// The tiles of a frame for one tile size, row by row, and the hash of each.
+struct TileGrid {
+    /// x, y, width and height of each tile.
+    rects: Vec<(u32, u32, u32, u32)>,
+    hashes: Vec<u64>,
+}
+
+/// The tiles of the last frame sent to a subscriber, to tell which ones changed.
+struct SentTiles {
+    width: u32,
+    height: u32,
+    tile_size: u32,
+    hashes: Vec<u64>,
 }
 
 impl StreamOptions {
-    pub fn new(refresh_rate: Option<u16>, quality: Option<u16>) -> Self {
+    /// Fails with what is wrong if the refresh rate, quality or size is out of range.
+    pub fn new(
+        refresh_rate: Option<u16>,
+        quality: Option<u16>,
+        encoding: Option<Encoding>,
+        subsampling: Option<ChromaSubsampling>,
+        transform: Transform,
+        delta: Option<DeltaOptions>,
+        defaults: &StreamDefaults,
+    ) -> Result<Self, String> {
+        if let Some(size) = transform.size
+            && (size.width > MAX_SIZE || size.height > MAX_SIZE)
+        {
+            return Err(format!(
+                "size: {}x{} is too large, expected at most {}x{}",
+                size.width, size.height, MAX_SIZE, MAX_SIZE
+            ));
+        }
+        Ok(Self {
+            refresh_rate: checked(
+                "refresh_rate",
+                refresh_rate.unwrap_or(defaults.refresh_rate),
+                &REFRESH_RATES,
+            )?,
+            quality: checked("quality", quality.unwrap_or(defaults.quality), &QUALITIES)?,
+            encoding,
+            subsampling: subsampling.unwrap_or_default(),
+            transform,
+            delta: delta.filter(|_| encoding.is_some()),
+            adaptive: None,
+        })
+    }
+
+    /// Lets the stream adapt to the link, starting out at the requested refresh rate and quality.
+    /// Fails with what is wrong if a bound is out of range.
+    pub fn adaptive(mut self, request: Option<AdaptiveRequest>) -> Result<Self, String> {
+        let Some(request) = request else {
+            return Ok(self);
+        };
+        let max_refresh_rate = checked(
+            "adaptive.max_refresh_rate",
+            request.max_refresh_rate.unwrap_or(self.refresh_rate),
+            &REFRESH_RATES,
+        )?;
+        let max_quality = checked(
+            "adaptive.max_quality",
+            request.max_quality.unwrap_or(self.quality),
+            &QUALITIES,
+        )?;
+        let bounds = AdaptiveBounds {
+            min_refresh_rate: checked(
+                "adaptive.min_refresh_rate",
+                request.min_refresh_rate.unwrap_or(DEFAULT_MIN_REFRESH_RATE),
+                &REFRESH_RATES,
+            )?
+            .min(max_refresh_rate),
+            max_refresh_rate,
+            min_quality: checked(
+                "adaptive.min_quality",
+                request.min_quality.unwrap_or(DEFAULT_MIN_QUALITY),
+                &QUALITIES,
+            )?
+            .min(max_quality),
+            max_quality,
+        };
+        self.refresh_rate = self
+            .refresh_rate
+            .clamp(bounds.min_refresh_rate, bounds.max_refresh_rate);
+        self.quality = self.quality.clamp(bounds.min_quality, bounds.max_quality);
+        self.adaptive = Some(bounds);
+        Ok(self)
+    }
+
+    /// Steps quality and then refresh rate down, returns false if both are at their minimum.
+    fn step_down(&mut self, bounds: &AdaptiveBounds) -> bool {
+        // blurry is better than choppy, so quality goes first.
+        if self.is_lossy() && self.quality > bounds.min_quality {
+            self.quality = self
+                .quality
+                .saturating_sub(QUALITY_STEP)
+                .max(bounds.min_quality);
+        } else if self.refresh_rate > bounds.min_refresh_rate {
+            self.refresh_rate = (self.refresh_rate * 3 / 4).max(bounds.min_refresh_rate);
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /// Steps refresh rate and then quality back up, returns false if both are at their maximum.
+    fn step_up(&mut self, bounds: &AdaptiveBounds) -> bool {
+        if self.refresh_rate < bounds.max_refresh_rate {
+            self.refresh_rate =
+                (self.refresh_rate + (self.refresh_rate / 4).max(1)).min(bounds.max_refresh_rate);
+        } else if self.is_lossy() && self.quality < bounds.max_quality {
+            self.quality = (self.quality + QUALITY_STEP).min(bounds.max_quality);
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /// Whether quality changes the bytes at all, lossless encodings ignore it.
+    fn is_lossy(&self) -> bool {
+        self.encoding.unwrap_or(Encoding::Jpeg).is_lossy()
+    }
+
+    pub fn encode_settings(&self) -> EncodeSettings {
+        let encoding = self.encoding.unwrap_or(Encoding::Jpeg);
+        // leave out what doesn't change the bytes, so more peers share an encode.
+        EncodeSettings {
+            encoding,
+            quality: if encoding.is_lossy() { self.quality } else { 0 },
+            subsampling: if encoding == Encoding::Jpeg {
+                self.subsampling
+            } else {
+                ChromaSubsampling::default()
+            },
+            transform: self.transform.clone(),
+        }
+    }
+
+    fn frame_interval(&self) -> Duration {
+        Duration::from_millis(1000 / self.refresh_rate as u64)
+    }
+}
+
+/// Checks a value of a stream request against the range the config allows for its default.
+fn checked(key: &str, value: u16, range: &RangeInclusive<u16>) -> Result<u16, String> {
+    config::check_range(value, range).map_err(|problem| format!("{}: {}", key, problem))
+}
+
+impl DeltaOptions {
+    pub fn new(request: DeltaRequest) -> Self {
+        Self {
+            tile_size: request
+                .tile_size
+                .unwrap_or(DEFAULT_TILE_SIZE)
+                .max(MIN_TILE_SIZE),
+            keyframe_interval: request
+                .keyframe_interval
+                .unwrap_or(DEFAULT_KEYFRAME_INTERVAL),
+        }
+    }
+}
+
+impl Transform {
+    /// Empty sizes and crops are ignored, there would be nothing left to stream.
+    pub fn new(size: Option<Size>, resize: Option<ResizeMode>, crop: Option<Rect>) -> Self {
+        Self {
+            crop: crop.filter(|crop| crop.width > 0 && crop.height > 0),
+            size: size.filter(|size| size.width > 0 && size.height > 0),
+            resize: resize.unwrap_or_default(),
+        }
+    }
+
+    /// Fails with what is wrong if the crop is not entirely within a texture of this size.
+    pub fn check(&self, (width, height): (u32, u32)) -> Result<(), String> {
+        match self.crop {
+            Some(crop)
+                if crop.x.saturating_add(crop.width) > width
+                    || crop.y.saturating_add(crop.height) > height =>
+            {
+                Err(format!(
+                    "crop of {}x{} at {},{} is not within the {}x{} texture",
+                    crop.width, crop.height, crop.x, crop.y, width, height
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn apply<'a>(&self, image: &'a RgbImage) -> Cow<'a, RgbImage> {
+        let mut image = Cow::Borrowed(image);
+        if let Some(crop) = self.crop {
+            let cropped =
+                imageops::crop_imm(image.as_ref(), crop.x, crop.y, crop.width, crop.height);
+            image = Cow::Owned(cropped.to_image());
+        }
+        if let Some(size) = self.size
+            && let Some(resized) = resize(&image, size, self.resize)
+        {
+            image = Cow::Owned(resized);
+        }
+        image
+    }
+}
+
+/// Scales the image keeping its aspect ratio, or returns None if it is fine as it is.
+fn resize(image: &RgbImage, mut size: Size, mode: ResizeMode) -> Option<RgbImage> {
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 {
+        return None;
+    }
+    if mode == ResizeMode::Fill {
+        // like Fit, never scale up, rather fill a smaller size with the same aspect ratio.
+        let shrink = (width as f64 / size.width as f64)
+            .min(height as f64 / size.height as f64)
+            .min(1.0);
+        size = Size {
+            width: ((size.width as f64 * shrink).round() as u32).clamp(1, width),
+            height: ((size.height as f64 * shrink).round() as u32).clamp(1, height),
+        };
+    }
+    if (width, height) == (size.width, size.height) {
+        return None;
+    }
+    let scale_x = size.width as f64 / width as f64;
+    let scale_y = size.height as f64 / height as f64;
+    let scaled = |scale: f64| {
+        (
+            ((width as f64 * scale).round() as u32).max(1),
+            ((height as f64 * scale).round() as u32).max(1),
+        )
+    };
+
+    match mode {
+        ResizeMode::Fit => {
+            let scale = scale_x.min(scale_y);
+            // scaling up would only cost bandwidth, the client can do that itself.
+            if scale >= 1.0 {
+                return None;
+            }
+            let (width, height) = scaled(scale);
+            Some(imageops::resize(image, width, height, FilterType::Lanczos3))
+        }
+        ResizeMode::Fill => {
+            let (width, height) = scaled(scale_x.max(scale_y));
+            let (width, height) = (width.max(size.width), height.max(size.height));
+            let resized = imageops::resize(image, width, height, FilterType::Lanczos3);
+            let x = (width - size.width) / 2;
+            let y = (height - size.height) / 2;
+            Some(imageops::crop_imm(&resized, x, y, size.width, size.height).to_image())
+        }
+    }
+}
+
+impl<'a> PreparedFrame<'a> {
+    fn new(image: &'a RgbImage, settings: EncodeSettings) -> Self {
         Self {
-            refresh_rate: refresh_rate.unwrap_or(30),
-            quality: quality.unwrap_or(65),
+            image: settings.transform.apply(image),
+            settings,
+            full: None,
+            hash: None,
+            grids: HashMap::new(),
+            tiles: HashMap::new(),
         }
     }
+
+    fn encode_full(&mut self) -> bool {
+        self.full
+            .get_or_insert_with(|| encode(&self.image, &self.settings))
+            .is_some()
+    }
+
+    fn hash(&mut self) -> u64 {
+        *self
+            .hash
+            .get_or_insert_with(|| seahash::hash(self.image.as_raw()))
+    }
+
+    fn grid(&mut self, tile_size: u32) -> &TileGrid {
+        self.grids.entry(tile_size).or_insert_with(|| {
+            let rects: Vec<_> = tiles(&self.image, tile_size).collect();
+            let hashes = rects
+                .iter()
+                .map(|&(x, y, width, height)| {
+                    let mut hasher = SeaHasher::new();
+                    for row in y..y + height {
+                        let start = ((row * self.image.width() + x) * 3) as usize;
+                        hasher.write(&self.image.as_raw()[start..start + width as usize * 3]);
+                    }
+                    hasher.finish()
+                })
+                .collect();
+            TileGrid { rects, hashes }
+        })
+    }
+
+    fn tile_hashes(&mut self, tile_size: u32) -> &[u64] {
+        &self.grid(tile_size).hashes
+    }
+
+    fn encode_tile(&mut self, tile_size: u32, index: usize) -> bool {
+        let Some(&(x, y, width, height)) = self.grid(tile_size).rects.get(index) else {
+            return false;
+        };
+        self.tiles
+            .entry((tile_size, index))
+            .or_insert_with(|| {
+                let tile = imageops::crop_imm(self.image.as_ref(), x, y, width, height).to_image();
+                encode(&tile, &self.settings)
+            })
+            .is_some()
+    }
+
+    fn tile(&self, tile_size: u32, index: usize) -> Option<Tile<'_>> {
+        let data = self.tiles.get(&(tile_size, index))?.as_deref()?;
+        let &(x, y, width, height) = self.grids.get(&tile_size)?.rects.get(index)?;
+        Some(Tile {
+            x,
+            y,
+            width,
+            height,
+            data,
+        })
+    }
+}
+
+/// The position and size of every tile, row by row. Tiles at the right and bottom edges may be smaller.
+fn tiles(image: &RgbImage, tile_size: u32) -> impl Iterator<Item = (u32, u32, u32, u32)> {
+    let (width, height) = image.dimensions();
+    (0..height).step_by(tile_size as usize).flat_map(move |y| {
+        (0..width)
+            .step_by(tile_size as usize)
+            .map(move |x| (x, y, tile_size.min(width - x), tile_size.min(height - y)))
+    })
+}
+
+/// What the stream remembers about each subscriber between frames.
+struct Subscriber {
+    /// What the peer asked for.
+    options: StreamOptions,
+    /// What the peer gets, differs from `options` while an adaptive stream is stepped down.
+    effective: StreamOptions,
+    last_adapted: Instant,
+    next_frame: Instant,
+    /// Of the texture as read for the last frame sent.
+    last_hash: Option<u64>,
+    /// Of the last frame sent after cropping and scaling, unless it was a delta stream.
+    last_frame_hash: Option<u64>,
+    read_failing: bool,
+    /// The crop does not fit the texture, the peer has been told.
+    crop_rejected: bool,
+    /// Frames sent so far.
+    sequence: u32,
+    /// Unchanged frames not sent since the last one that was.
+    skipped: u32,
+    /// What the last frame was made of, for delta streams.
+    sent_tiles: Option<SentTiles>,
+    frames_since_keyframe: u32,
+    keyframe_requested: bool,
 }
 
 impl TextureStream {
     pub fn new(
         cancellation_token: Arc<AtomicBool>,
-        stream_key: StreamKey,
-        stream_options: StreamOptions,
-        tx: Sender<PacketData>,
+        texture_id: TextureId,
+        subscribers: Subscribers,
+        keyframe_requests: KeyframeRequests,
+        link_stats: LinkStats,
+        texture_config: TextureConfig,
+        tx: Sender<Outgoing>,
     ) -> Self {
         Self {
             cancellation_token,
-            stream_key,
-            stream_options,
+            texture_id,
+            subscribers,
+            keyframe_requests,
+            link_stats,
+            texture_config,
             tx,
-            last_hash: None,
         }
     }
 
     pub fn run(&mut self) {
+        let mut subscribers = HashMap::new();
+        let mut source = texture_reader::create_texture_source(&self.texture_config);
+
         loop {
             if self.cancellation_token.load(Ordering::Relaxed) {
-                debug!("Cancelled streaming {:?}", self.stream_key);
+                debug!("Cancelled streaming {:?}", self.texture_id);
                 break;
             }
 
-            thread::sleep(Duration::from_millis(
-                1000 / self.stream_options.refresh_rate as u64,
-            ));
+            self.sync_subscribers(&mut subscribers);
+            self.adapt(&mut subscribers);
 
-            let texture_id: TextureId = self.stream_key.identifier.as_str().into();
-            let data = texture_reader::rtt_texture_read(texture_id.clone());
+            let now = Instant::now();
+            let Some(next_frame) = subscribers.values().map(|s| s.next_frame).min() else {
+                thread::sleep(Duration::from_millis(10));
+                continue;
+            };
+            if next_frame > now {
+                thread::sleep(next_frame - now);
+                continue;
+            }
 
-            if let Ok(image) = data {
-                let hash = seahash::hash(image.as_raw());
+            let data = source.read(self.texture_id);
+            let captured_at = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since_epoch| since_epoch.as_millis() as u64);
 
-                if let Some(last_hash) = self.last_hash
-                    && last_hash == hash
-                {
-                    // the last sent frame is the same as this one.
-                    continue;
-                }
+            match data {
+                Ok(image) => self.send_frame(&mut subscribers, now, captured_at, &image),
+                Err(e) => self.report_failure(&mut subscribers, now, e),
+            }
+        }
+    }
 
-                let tj_image = Image {
-                    pixels: image.as_raw().as_slice(),
-                    width: image.width() as usize,
-                    pitch: image.width() as usize * 3, // 3 bytes per pixel (RGB)
-                    height: image.height() as usize,
-                    format: PixelFormat::RGB,
-                };
+    /// Picks up peers that subscribed or unsubscribed since the last frame.
+    fn sync_subscribers(&self, subscribers: &mut HashMap<ClientId, Subscriber>) {
+        let requested = lock(&self.subscribers);
+        subscribers.retain(|peer_id, _| requested.contains_key(peer_id));
+        for (peer_id, options) in requested.iter() {
+            subscribers
+                .entry(*peer_id)
+                .and_modify(|s| {
+                    if s.options != *options {
+                        // the last frame in the old format says nothing about the new one.
+                        s.options = options.clone();
+                        s.effective = options.clone();
+                        s.last_hash = None;
+                        s.last_frame_hash = None;
+                        s.crop_rejected = false;
+                        s.sent_tiles = None;
+                        s.keyframe_requested = true;
+                    }
+                })
+                .or_insert_with(|| Subscriber {
+                    options: options.clone(),
+                    effective: options.clone(),
+                    last_adapted: Instant::now(),
+                    next_frame: Instant::now(),
+                    last_hash: None,
+                    last_frame_hash: None,
+                    read_failing: false,
+                    crop_rejected: false,
+                    sequence: 0,
+                    skipped: 0,
+                    sent_tiles: None,
+                    frames_since_keyframe: 0,
+                    keyframe_requested: false,
+                });
+        }
+
+        for peer_id in lock(&self.keyframe_requests).drain() {
+            if let Some(subscriber) = subscribers.get_mut(&peer_id) {
+                subscriber.keyframe_requested = true;
+            }
+        }
+    }
+
+    /// Steps adaptive streams down while the peer's link is struggling and back up once it recovered.
+    fn adapt(&self, subscribers: &mut HashMap<ClientId, Subscriber>) {
+        let link_stats = lock(&self.link_stats);
+        for (peer_id, subscriber) in subscribers.iter_mut() {
+            let Some(bounds) = subscriber.options.adaptive else {
+                continue;
+            };
+            let Some(link) = link_stats.get(peer_id) else {
+                continue;
+            };
+            if subscriber.last_adapted.elapsed() < ADAPT_INTERVAL {
+                continue;
+            }
+            subscriber.last_adapted = Instant::now();
 
-                // make it a jpeg as requested
-                let bytes = turbojpeg::compress(
-                    tj_image,
-                    self.stream_options.quality.into(),
-                    turbojpeg::Subsamp::Sub2x2,
+            let degraded = link.packet_loss > DEGRADED_PACKET_LOSS
+                || link.round_trip_time > DEGRADED_ROUND_TRIP_TIME;
+            let healthy = link.packet_loss < HEALTHY_PACKET_LOSS
+                && link.round_trip_time < HEALTHY_ROUND_TRIP_TIME;
+            let stepped = if degraded {
+                subscriber.effective.step_down(&bounds)
+            } else if healthy {
+                subscriber.effective.step_up(&bounds)
+            } else {
+                false
+            };
+            if stepped {
+                debug!(
+                    "Streaming {:?} to {:?} at {} Hz, quality {}, link: {:?}",
+                    self.texture_id,
+                    peer_id,
+                    subscriber.effective.refresh_rate,
+                    subscriber.effective.quality,
+                    link
                 );
+            }
+        }
+    }
+
+    fn send_frame(
+        &self,
+        subscribers: &mut HashMap<ClientId, Subscriber>,
+        now: Instant,
+        captured_at: u64,
+        image: &RgbImage,
+    ) {
+        let hash = seahash::hash(image.as_raw());
+        let mut prepared: HashMap<EncodeSettings, PreparedFrame> = HashMap::new();
 
-                match bytes {
-                    Ok(bytes) => {
-                        let packet_data = PacketData {
-                            peer_id: self.stream_key.peer_id,
-                            data: bytes.to_vec(),
-                            channel: texture_id as u8,
-                        };
+        for (peer_id, subscriber) in subscribers.iter_mut() {
+            if subscriber.next_frame > now {
+                continue;
+            }
+            subscriber.next_frame = now + subscriber.effective.frame_interval();
+            subscriber.read_failing = false;
 
-                        if let Err(e) = self.tx.send(packet_data) {
-                            error!("Failed to send packet_data: {}", e)
+            if let Err(problem) = subscriber.effective.transform.check(image.dimensions()) {
+                // the texture could change size, keep checking but only tell the client once.
+                if !subscriber.crop_rejected {
+                    subscriber.crop_rejected = true;
+                    let message = format!(
+                        "Not streaming {}: {}",
+                        self.texture_id.identifier(),
+                        problem
+                    );
+                    error!("{} to {:?}", message, peer_id);
+                    enet_server::send_message(
+                        &self.tx,
+                        *peer_id,
+                        &ProtocolMessage::error(None, ErrorCode::InvalidValue, message),
+                    );
+                }
+                continue;
+            }
+            subscriber.crop_rejected = false;
+
+            if subscriber.last_hash == Some(hash) && !subscriber.keyframe_requested {
+                // the last sent frame is the same as this one.
+                subscriber.skipped += 1;
+                continue;
+            }
+
+            let settings = subscriber.effective.encode_settings();
+            let frame = prepared
+                .entry(settings)
+                .or_insert_with_key(|settings| PreparedFrame::new(image, settings.clone()));
+            let (width, height) = frame.image.dimensions();
+            let frame_hash = subscriber.effective.delta.is_none().then(|| frame.hash());
+
+            // for delta streams, the hashes of this frame's tiles and which of them changed.
+            // no changed tiles means this is a keyframe.
+            let mut tile_hashes = None;
+            let mut changed_tiles = None;
+            if let Some(delta) = subscriber.effective.delta {
+                let hashes = frame.tile_hashes(delta.tile_size).to_vec();
+                let sent = subscriber.sent_tiles.as_ref().filter(|sent| {
+                    (sent.width, sent.height, sent.tile_size) == (width, height, delta.tile_size)
+                });
+                if let Some(sent) = sent
+                    && !subscriber.keyframe_requested
+                    && subscriber.frames_since_keyframe < delta.keyframe_interval
+                {
+                    let changed: Vec<usize> = (0..hashes.len())
+                        .filter(|i| hashes[*i] != sent.hashes[*i])
+                        .collect();
+                    if changed.is_empty() {
+                        // whatever changed was cropped away.
+                        subscriber.last_hash.replace(hash);
+                        subscriber.skipped += 1;
+                        continue;
+                    }
+                    changed_tiles = Some(changed);
+                }
+                tile_hashes = Some((delta.tile_size, hashes));
+            } else if frame_hash == subscriber.last_frame_hash && !subscriber.keyframe_requested {
+                // whatever changed was cropped away, or scaled down to nothing.
+                subscriber.last_hash.replace(hash);
+                subscriber.skipped += 1;
+                continue;
+            }
+
+            // this is okay for now, we'll try again on the next frame.
+            let encoded = match (&changed_tiles, &tile_hashes) {
+                (Some(changed), Some((tile_size, _))) => changed
+                    .iter()
+                    .all(|index| frame.encode_tile(*tile_size, *index)),
+                _ => frame.encode_full(),
+            };
+            if !encoded {
+                continue;
+            }
+
+            let full = frame.full.as_ref().and_then(|full| full.as_deref());
+            let data = match subscriber.effective.encoding {
+                // clients that did not choose an encoding expect the bare JPEG.
+                None => full.unwrap_or_default().to_vec(),
+                Some(encoding) => {
+                    let tiles = match (&changed_tiles, &tile_hashes) {
+                        (Some(changed), Some((tile_size, _))) => changed
+                            .iter()
+                            .filter_map(|index| frame.tile(*tile_size, *index))
+                            .collect(),
+                        _ => vec![],
+                    };
+                    let frame = Frame {
+                        identifier: self.texture_id.identifier(),
+                        sequence: subscriber.sequence,
+                        captured_at,
+                        width,
+                        height,
+                        encoding,
+                        quality: encoding.is_lossy().then_some(subscriber.effective.quality),
+                        skipped: subscriber.skipped,
+                        keyframe: changed_tiles.is_none(),
+                        data: if changed_tiles.is_none() {
+                            full.unwrap_or_default()
                         } else {
-                            self.last_hash.replace(hash);
+                            &[]
+                        },
+                        tiles,
+                    };
+                    match rmp_serde::to_vec_named(&frame) {
+                        Ok(data) => data,
+                        Err(e) => {
+                            error!("Failed to serialize frame: {}", e);
+                            continue;
                         }
                     }
-                    Err(_) => {
-                        // this is okay for now, we'll try again on the next frame.
-                    }
                 }
+            };
+
+            let packet_data = PacketData {
+                peer_id: *peer_id,
+                data,
+                channel: self.texture_id as u8,
+                delivery: Delivery::Unreliable,
+            };
+
+            if let Err(e) = self.tx.send(Outgoing::Packet(packet_data)) {
+                error!("Failed to send packet_data: {}", e)
             } else {
-                // TODO: for now this is okay
-                // error!("Failed to read texture data for: {:?}", texture_id)
+                subscriber.last_hash.replace(hash);
+                subscriber.last_frame_hash = frame_hash;
+                subscriber.sequence = subscriber.sequence.wrapping_add(1);
+                subscriber.skipped = 0;
+                subscriber.keyframe_requested = false;
+                subscriber.frames_since_keyframe = match changed_tiles {
+                    Some(_) => subscriber.frames_since_keyframe + 1,
+                    None => 0,
+                };
+                subscriber.sent_tiles = tile_hashes.map(|(tile_size, hashes)| SentTiles {
+                    width,
+                    height,
+                    tile_size,
+                    hashes,
+                });
+            }
+        }
+    }
+
+    fn report_failure(
+        &self,
+        subscribers: &mut HashMap<ClientId, Subscriber>,
+        now: Instant,
+        e: std::io::Error,
+    ) {
+        let code = match e.kind() {
+            std::io::ErrorKind::NotConnected => ErrorCode::BmsNotRunning,
+            _ => ErrorCode::TextureReadFailed,
+        };
+
+        for (peer_id, subscriber) in subscribers.iter_mut() {
+            if subscriber.next_frame > now {
+                continue;
+            }
+            subscriber.next_frame = now + subscriber.effective.frame_interval();
+
+            // only tell the client once, BMS not running is going to last a while.
+            if subscriber.read_failing {
+                continue;
+            }
+            subscriber.read_failing = true;
+            subscriber.last_hash = None;
+            subscriber.last_frame_hash = None;
+            enet_server::send_message(
+                &self.tx,
+                *peer_id,
+                &ProtocolMessage::error(
+                    None,
+                    code,
+                    format!("Failed to read {}: {}", self.texture_id.identifier(), e),
+                ),
+            );
+        }
+    }
+}
+
+fn encode(image: &RgbImage, settings: &EncodeSettings) -> Option<Vec<u8>> {
+    match settings.encoding {
+        Encoding::Jpeg => encode_jpeg(image, settings),
+        Encoding::Png => encode_image(image, ImageFormat::Png),
+        Encoding::Qoi => encode_image(image, ImageFormat::Qoi),
+        encoding @ (Encoding::Webp | Encoding::WebpLossless) => {
+            let encoder = webp::Encoder::from_rgb(image.as_raw(), image.width(), image.height());
+            encoder
+                .encode_simple(encoding == Encoding::WebpLossless, settings.quality.into())
+                .map(|bytes| bytes.to_vec())
+                .ok()
+        }
+    }
+}
+
+fn encode_image(image: &RgbImage, format: ImageFormat) -> Option<Vec<u8>> {
+    let mut bytes = Cursor::new(Vec::new());
+    image.write_to(&mut bytes, format).ok()?;
+    Some(bytes.into_inner())
+}
+
+fn encode_jpeg(image: &RgbImage, settings: &EncodeSettings) -> Option<Vec<u8>> {
+    let tj_image = Image {
+        pixels: image.as_raw().as_slice(),
+        width: image.width() as usize,
+        pitch: image.width() as usize * 3, // 3 bytes per pixel (RGB)
+        height: image.height() as usize,
+        format: PixelFormat::RGB,
+    };
+
+    let subsamp = match settings.subsampling {
+        ChromaSubsampling::None => Subsamp::None,
+        ChromaSubsampling::Half => Subsamp::Sub2x1,
+        ChromaSubsampling::Quarter => Subsamp::Sub2x2,
+    };
+
+    // make it a jpeg as requested
+    turbojpeg::compress(tj_image, settings.quality.into(), subsamp)
+        .map(|bytes| bytes.to_vec())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, sync::mpsc};
+
+    use image::Rgb;
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::config::TextureSourceKind;
+    use crate::state::{InnerState, RunningStream, State};
+
+    /// The parts of a `Frame` the tests look at.
+    #[derive(Debug, Deserialize)]
+    struct ReceivedFrame {
+        identifier: String,
+        sequence: u32,
+        width: u32,
+        height: u32,
+        encoding: Encoding,
+        keyframe: bool,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+        tiles: Vec<ReceivedTile>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct ReceivedTile {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    }
+
+    struct RunningTestStream {
+        state: State,
+        texture_id: TextureId,
+        rx: mpsc::Receiver<Outgoing>,
+    }
+
+    impl RunningTestStream {
+        fn start(texture_id: TextureId, directory: PathBuf, options: StreamOptions) -> Self {
+            let state = State::new(InnerState::new(
+                Arc::new(AtomicBool::new(false)),
+                config::Config::default().streams,
+            ));
+            let mut stream = RunningStream::new();
+            lock(&stream.subscribers).insert(ClientId(1), options);
+            let (tx, rx) = mpsc::channel();
+            let mut texture_stream = TextureStream::new(
+                stream.cancellation_token.clone(),
+                texture_id,
+                stream.subscribers.clone(),
+                stream.keyframe_requests.clone(),
+                state.link_stats.clone(),
+                TextureConfig {
+                    source: TextureSourceKind::Files,
+                    directory: directory.to_string_lossy().to_string(),
+                },
+                tx,
+            );
+            stream.thread = Some(thread::spawn(move || texture_stream.run()));
+            lock(&state.streams_running).insert(texture_id, stream);
+            Self {
+                state,
+                texture_id,
+                rx,
             }
         }
+
+        /// Changes the options, like a client sending another `Start` would.
+        fn restart(&self, options: StreamOptions) {
+            let streams = lock(&self.state.streams_running);
+            lock(&streams[&self.texture_id].subscribers).insert(ClientId(1), options);
+        }
+
+        fn next_packet(&self) -> Option<PacketData> {
+            match self.rx.recv_timeout(Duration::from_secs(5)).ok()? {
+                Outgoing::Packet(packet) => Some(packet),
+                outgoing => panic!("Expected a packet, got {:?}", outgoing),
+            }
+        }
+
+        fn next_frame(&self) -> ReceivedFrame {
+            let packet = self.next_packet().expect("No frame was sent");
+            assert_eq!(packet.peer_id, ClientId(1));
+            assert_eq!(packet.delivery, Delivery::Unreliable);
+            rmp_serde::from_slice(&packet.data).expect("Not a frame")
+        }
+    }
+
+    impl Drop for RunningTestStream {
+        fn drop(&mut self) {
+            self.state.stop_all_streams();
+        }
+    }
+
+    fn fixtures() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("images")
+    }
+
+    /// A fresh directory for images made up by a test.
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!(
+            "falcon-bms-control-server-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn options(encoding: Option<Encoding>, transform: Transform) -> StreamOptions {
+        let defaults = StreamDefaults {
+            refresh_rate: 30,
+            quality: 65,
+        };
+        StreamOptions::new(Some(60), None, encoding, None, transform, None, &defaults).unwrap()
+    }
+
+    fn delta_options(keyframe_interval: u32) -> StreamOptions {
+        StreamOptions {
+            delta: Some(DeltaOptions {
+                tile_size: 16,
+                keyframe_interval,
+            }),
+            ..options(Some(Encoding::Png), Transform::default())
+        }
+    }
+
+    /// Two black 64x32 images, the second with a red pixel in the tile at 32,16.
+    fn one_tile_changing(name: &str) -> PathBuf {
+        let directory = test_directory(name);
+        fs::create_dir(directory.join("ded")).unwrap();
+        let mut image = RgbImage::new(64, 32);
+        image.save(directory.join("ded").join("0.png")).unwrap();
+        image.put_pixel(40, 20, Rgb([255, 0, 0]));
+        image.save(directory.join("ded").join("1.png")).unwrap();
+        directory
+    }
+
+    #[test]
+    fn steps_only_the_refresh_rate_of_lossless_streams() {
+        let request = AdaptiveRequest {
+            min_refresh_rate: Some(10),
+            max_refresh_rate: None,
+            min_quality: Some(5),
+            max_quality: None,
+        };
+        let mut png = options(Some(Encoding::Png), Transform::default())
+            .adaptive(Some(request))
+            .unwrap();
+        let bounds = png.adaptive.unwrap();
+
+        assert!(png.step_down(&bounds));
+        assert_eq!((png.refresh_rate, png.quality), (45, 65));
+        while png.step_down(&bounds) {}
+        assert_eq!((png.refresh_rate, png.quality), (10, 65));
+        while png.step_up(&bounds) {}
+        assert_eq!((png.refresh_rate, png.quality), (60, 65));
+
+        let mut jpeg = options(None, Transform::default())
+            .adaptive(Some(request))
+            .unwrap();
+        assert!(jpeg.step_down(&bounds));
+        assert_eq!((jpeg.refresh_rate, jpeg.quality), (60, 55));
+    }
+
+    #[test]
+    fn streams_a_still_image_once() {
+        let stream = RunningTestStream::start(
+            TextureId::Ded,
+            fixtures(),
+            options(Some(Encoding::Png), Transform::default()),
+        );
+
+        let frame = stream.next_frame();
+        let fixture = image::open(fixtures().join("ded.jpeg")).unwrap();
+        assert_eq!(frame.identifier, "f16/ded");
+        assert_eq!(frame.sequence, 0);
+        assert_eq!(frame.encoding, Encoding::Png);
+        assert!(frame.keyframe);
+        assert_eq!(
+            (frame.width, frame.height),
+            (fixture.width(), fixture.height())
+        );
+        assert_eq!(
+            image::load_from_memory(&frame.data).unwrap().to_rgb8(),
+            fixture.to_rgb8()
+        );
+
+        // the image does not change, so there is nothing more to send.
+        assert!(stream.rx.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+
+    #[test]
+    fn sends_the_still_image_again_when_the_options_change() {
+        let stream = RunningTestStream::start(
+            TextureId::Ded,
+            fixtures(),
+            options(Some(Encoding::Png), Transform::default()),
+        );
+        assert_eq!(stream.next_frame().encoding, Encoding::Png);
+        assert!(stream.rx.recv_timeout(Duration::from_millis(200)).is_err());
+
+        stream.restart(options(Some(Encoding::Qoi), Transform::default()));
+        let frame = stream.next_frame();
+        assert_eq!(frame.encoding, Encoding::Qoi);
+        assert!(frame.keyframe);
+    }
+
+    #[test]
+    fn streams_an_image_sequence_in_order() {
+        let directory = test_directory("sequence");
+        fs::create_dir(directory.join("ded")).unwrap();
+        for (i, color) in [[255, 0, 0], [0, 255, 0]].into_iter().enumerate() {
+            RgbImage::from_pixel(8, 4, Rgb(color))
+                .save(directory.join("ded").join(format!("{}.png", i)))
+                .unwrap();
+        }
+
+        let stream = RunningTestStream::start(
+            TextureId::Ded,
+            directory.clone(),
+            options(Some(Encoding::Qoi), Transform::default()),
+        );
+        for (sequence, color) in [[255, 0, 0], [0, 255, 0], [255, 0, 0]]
+            .into_iter()
+            .enumerate()
+        {
+            let frame = stream.next_frame();
+            assert_eq!(frame.sequence, sequence as u32);
+            let image = image::load_from_memory(&frame.data).unwrap().to_rgb8();
+            assert_eq!(image.dimensions(), (8, 4));
+            assert_eq!(image.get_pixel(0, 0), &Rgb(color));
+        }
+
+        drop(stream);
+        let _ = fs::remove_dir_all(directory);
+    }
+
+    #[test]
+    fn skips_frames_that_only_changed_outside_of_the_crop() {
+        let directory = test_directory("cropped");
+        fs::create_dir(directory.join("ded")).unwrap();
+        for i in 0..2 {
+            let mut image = RgbImage::from_pixel(8, 4, Rgb([255, 0, 0]));
+            image.put_pixel(7, 3, Rgb([0, 255 * i, 0]));
+            image
+                .save(directory.join("ded").join(format!("{}.png", i)))
+                .unwrap();
+        }
+        let crop = Rect {
+            x: 0,
+            y: 0,
+            width: 4,
+            height: 4,
+        };
+
+        let stream = RunningTestStream::start(
+            TextureId::Ded,
+            directory.clone(),
+            options(Some(Encoding::Qoi), Transform::new(None, None, Some(crop))),
+        );
+        assert_eq!(stream.next_frame().sequence, 0);
+        assert!(stream.rx.recv_timeout(Duration::from_millis(200)).is_err());
+
+        drop(stream);
+        let _ = fs::remove_dir_all(directory);
+    }
+
+    #[test]
+    fn streams_bare_jpeg_without_an_encoding() {
+        let stream = RunningTestStream::start(
+            TextureId::LeftMfd,
+            fixtures(),
+            options(None, Transform::default()),
+        );
+
+        let packet = stream.next_packet().expect("No frame was sent");
+        assert_eq!(packet.channel, TextureId::LeftMfd as u8);
+        assert_eq!(
+            image::guess_format(&packet.data).unwrap(),
+            ImageFormat::Jpeg
+        );
+    }
+
+    #[test]
+    fn shares_the_jpeg_between_clients_with_and_without_an_encoding() {
+        let bare = options(None, Transform::default());
+        let jpeg = options(Some(Encoding::Jpeg), Transform::default());
+        assert_eq!(bare.encode_settings(), jpeg.encode_settings());
+    }
+
+    #[test]
+    fn tells_the_client_when_there_is_no_image() {
+        let directory = test_directory("missing");
+        let stream = RunningTestStream::start(
+            TextureId::Rwr,
+            directory.clone(),
+            options(Some(Encoding::Png), Transform::default()),
+        );
+
+        let packet = stream.next_packet().expect("No error was sent");
+        let message: ProtocolMessage = rmp_serde::from_slice(&packet.data).unwrap();
+        assert!(matches!(
+            message,
+            ProtocolMessage::Error {
+                code: ErrorCode::TextureReadFailed,
+                ..
+            }
+        ));
+        // only once, not for every frame.
+        assert!(stream.rx.recv_timeout(Duration::from_millis(200)).is_err());
+
+        drop(stream);
+        let _ = fs::remove_dir_all(directory);
+    }
+
+    #[test]
+    fn rejects_a_crop_outside_of_the_texture() {
+        let crop = Rect {
+            x: 10_000,
+            y: 0,
+            width: 100,
+            height: 100,
+        };
+        let stream = RunningTestStream::start(
+            TextureId::Ded,
+            fixtures(),
+            options(Some(Encoding::Png), Transform::new(None, None, Some(crop))),
+        );
+
+        let packet = stream.next_packet().expect("No error was sent");
+        let message: ProtocolMessage = rmp_serde::from_slice(&packet.data).unwrap();
+        assert!(matches!(
+            message,
+            ProtocolMessage::Error {
+                code: ErrorCode::InvalidValue,
+                ..
+            }
+        ));
+        assert!(stream.rx.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+
+    #[test]
+    fn rejects_sizes_larger_than_the_maximum() {
+        let defaults = config::Config::default().streams;
+        let size = Size {
+            width: 1024,
+            height: MAX_SIZE + 1,
+        };
+        let transform = Transform::new(Some(size), Some(ResizeMode::Fill), None);
+        let result = StreamOptions::new(None, None, None, None, transform, None, &defaults);
+        assert_eq!(
+            result.unwrap_err(),
+            "size: 1024x4097 is too large, expected at most 4096x4096"
+        );
+    }
+
+    #[test]
+    fn fills_sizes_larger_than_the_texture_without_scaling_up() {
+        let image = RgbImage::new(200, 100);
+        let large = Size {
+            width: 4000,
+            height: 4000,
+        };
+        let filled = resize(&image, large, ResizeMode::Fill).unwrap();
+        assert_eq!(filled.dimensions(), (100, 100));
+
+        let same = Size {
+            width: 200,
+            height: 100,
+        };
+        assert!(resize(&image, same, ResizeMode::Fill).is_none());
+    }
+
+    #[test]
+    fn sends_only_the_tile_that_changed() {
+        let directory = one_tile_changing("delta");
+        let stream = RunningTestStream::start(TextureId::Ded, directory.clone(), delta_options(60));
+
+        let keyframe = stream.next_frame();
+        assert!(keyframe.keyframe);
+        assert!(keyframe.tiles.is_empty());
+        assert_eq!(
+            image::load_from_memory(&keyframe.data)
+                .unwrap()
+                .to_rgb8()
+                .dimensions(),
+            (64, 32)
+        );
+
+        let frame = stream.next_frame();
+        assert_eq!(frame.sequence, 1);
+        assert!(!frame.keyframe);
+        assert!(frame.data.is_empty());
+        assert_eq!((frame.width, frame.height), (64, 32));
+        assert_eq!(frame.tiles.len(), 1);
+        let tile = &frame.tiles[0];
+        assert_eq!((tile.x, tile.y, tile.width, tile.height), (32, 16, 16, 16));
+        let image = image::load_from_memory(&tile.data).unwrap().to_rgb8();
+        assert_eq!(image.dimensions(), (16, 16));
+        assert_eq!(image.get_pixel(8, 4), &Rgb([255, 0, 0]));
+
+        drop(stream);
+        let _ = fs::remove_dir_all(directory);
+    }
+
+    #[test]
+    fn sends_a_keyframe_every_keyframe_interval() {
+        let directory = one_tile_changing("keyframe-interval");
+        let stream = RunningTestStream::start(TextureId::Ded, directory.clone(), delta_options(3));
+
+        let keyframes: Vec<bool> = (0..9).map(|_| stream.next_frame().keyframe).collect();
+        assert_eq!(
+            keyframes,
+            [true, false, false, false, true, false, false, false, true]
+        );
+
+        drop(stream);
+        let _ = fs::remove_dir_all(directory);
+    }
+
+    #[test]
+    fn sends_a_keyframe_when_the_client_asks_for_one() {
+        let stream = RunningTestStream::start(TextureId::Ded, fixtures(), delta_options(60));
+        assert!(stream.next_frame().keyframe);
+        assert!(stream.rx.recv_timeout(Duration::from_millis(200)).is_err());
+
+        assert!(stream.state.request_keyframe(TextureId::Ded, ClientId(1)));
+        let frame = stream.next_frame();
+        assert_eq!(frame.sequence, 1);
+        assert!(frame.keyframe);
+        assert!(!frame.data.is_empty());
+        assert!(!stream.state.request_keyframe(TextureId::Ded, ClientId(2)));
     }
 }
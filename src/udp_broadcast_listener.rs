@@ -1,29 +1,87 @@
-use std::{net::UdpSocket, time::Duration};
+use std::{
+    collections::BTreeSet,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket},
+    time::Duration,
+};
 
-use crate::{State, msgpack::ProtocolMessage};
-use log::{debug, error, info};
+use if_addrs::IfAddr;
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::{
+    State, VERSION,
+    error::{self, Error},
+    msgpack::ProtocolMessage,
+    state::lock,
+};
+use log::{debug, error, info, warn};
+
+/// IPv6 has no broadcast, clients send their hello to this link-local multicast group instead.
+pub const DISCOVERY_MULTICAST_GROUP: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfbc5);
 
 pub struct UdpBroadcastListener {
     state: State,
-    socket: UdpSocket,
+    /// One socket per IP version, each answering the hellos that arrive on it.
+    sockets: Vec<UdpSocket>,
+    name: String,
+    /// The address ENet listens on, unless it listens on all of them.
+    enet_address: Option<IpAddr>,
+    enet_port: u16,
 }
 
 impl UdpBroadcastListener {
-    pub fn new(address: String, port: u16, state: State) -> Self {
-        let socket = UdpSocket::bind(format!("{}:{}", address, port)).unwrap();
-        socket
-            .set_nonblocking(true)
-            .expect("Failed to set socket to non-blocking");
-        info!("Listening for broadcast packets on {}:{}", address, port);
-        Self { state, socket }
+    pub fn new(
+        address: String,
+        port: u16,
+        name: String,
+        enet_port: u16,
+        state: State,
+    ) -> error::Result<Self> {
+        let ip = address
+            .parse::<IpAddr>()
+            .map_err(|_| Error::InvalidAddress {
+                key: "listen_address",
+                value: address,
+            })?;
+        let address = SocketAddr::new(ip, port);
+        let bind_error = |source| Error::Bind {
+            what: "broadcast packets",
+            address: address.to_string(),
+            source,
+        };
+        let sockets = match ip {
+            IpAddr::V4(_) => vec![UdpSocket::bind(address)],
+            // not every system lets an IPv6 socket receive IPv4 broadcasts, so each gets its own.
+            IpAddr::V6(ip) if ip.is_unspecified() => vec![
+                UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port)),
+                bind_v6(address),
+            ],
+            IpAddr::V6(_) => vec![bind_v6(address)],
+        };
+        let sockets = sockets
+            .into_iter()
+            .map(|socket| {
+                let socket = socket?;
+                socket.set_nonblocking(true)?;
+                info!(
+                    "Listening for broadcast packets on {}",
+                    socket.local_addr()?
+                );
+                Ok(socket)
+            })
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(bind_error)?;
+        Ok(Self {
+            state,
+            sockets,
+            name,
+            enet_address: Some(ip).filter(|ip| !ip.is_unspecified()),
+            enet_port,
+        })
     }
 
     pub fn run(&mut self) {
-        let &mut UdpBroadcastListener {
-            state: _,
-            ref socket,
-        } = self;
-
         let mut buf = [0u8; 1024];
 
         loop {
@@ -35,34 +93,228 @@ impl UdpBroadcastListener {
                 break;
             }
 
-            let recv = socket.recv_from(&mut buf);
-            match recv {
-                Ok((size, addr)) => {
-                    let message: Result<ProtocolMessage, rmp_serde::decode::Error> =
-                        rmp_serde::from_slice(&buf[..size]);
-                    match message {
-                        Ok(message) => match message {
-                            ProtocolMessage::Hello {} => {
-                                debug!("Received packet from {}", addr);
-                                let message = ProtocolMessage::Ack {};
-                                let bytes = rmp_serde::to_vec_named(&message)
-                                    .expect("Failed to serialize ack message");
-                                socket
-                                    .send_to(&bytes, addr)
-                                    .expect("Failed to send ack message to peer");
-                            }
-                            m => error!("Received unexpected message via udp: {:?}", m),
-                        },
-                        Err(e) => error!("Failed to parse message received via UDP: {}", e),
+            let mut idle = true;
+            for socket in &self.sockets {
+                match socket.recv_from(&mut buf) {
+                    Ok((size, addr)) => {
+                        idle = false;
+                        self.handle_packet(socket, &buf[..size], addr);
+                    }
+                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                    Err(e) => {
+                        error!("UDP error: {}", e);
+                    }
+                }
+            }
+            if idle {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        }
+    }
+
+    fn handle_packet(&self, socket: &UdpSocket, packet: &[u8], addr: SocketAddr) {
+        let message: Result<ProtocolMessage, rmp_serde::decode::Error> =
+            rmp_serde::from_slice(packet);
+        match message {
+            Ok(message) => match message {
+                ProtocolMessage::Hello { .. } => {
+                    debug!("Received packet from {}", addr);
+                    let message = self.ack(addr);
+                    let bytes =
+                        rmp_serde::to_vec_named(&message).expect("Failed to serialize ack message");
+                    if let Err(e) = socket.send_to(&bytes, addr) {
+                        error!("Failed to send ack message to {}: {}", addr, e);
                     }
                 }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    std::thread::sleep(Duration::from_millis(100));
+                m => error!("Received unexpected message via udp: {:?}", m),
+            },
+            Err(e) => error!("Failed to parse message received via UDP: {}", e),
+        }
+    }
+
+    fn ack(&self, to: SocketAddr) -> ProtocolMessage {
+        let sim_info = lock(&self.state.sim_info).clone();
+        let address = match (self.enet_address, to) {
+            (Some(ip), _) => Some(ip.to_string()),
+            // ENet is IPv4 only, so the address this reply comes from is of no use.
+            (None, SocketAddr::V6(to)) => {
+                let ip = ipv4_address(&to);
+                if ip.is_none() {
+                    warn!("No IPv4 address to tell {} to connect to", to);
                 }
-                Err(e) => {
-                    error!("UDP error: {}", e);
+                ip.map(|ip| ip.to_string())
+            }
+            (None, SocketAddr::V4(_)) => None,
+        };
+        ProtocolMessage::Ack {
+            name: self.name.clone(),
+            server_version: VERSION.to_string(),
+            port: self.enet_port,
+            address,
+            bms_running: sim_info.bms_running,
+            theater: sim_info.theater,
+            aircraft: sim_info.aircraft,
+            clients: lock(&self.state.connected_peers).len(),
+        }
+    }
+}
+
+/// Binds an IPv6 only socket that also receives the hellos sent to the discovery
+/// multicast group on every interface.
+fn bind_v6(address: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(true)?;
+    socket.bind(&address.into())?;
+    // the group is link-local, it has to be joined on each link clients may be on.
+    // Multicast discovery is a bonus, broadcast still works without it.
+    let mut joined = 0;
+    for index in multicast_interfaces() {
+        match socket.join_multicast_v6(&DISCOVERY_MULTICAST_GROUP, index) {
+            Ok(()) => joined += 1,
+            Err(e) => debug!(
+                "Could not join multicast group {} on interface {}: {}",
+                DISCOVERY_MULTICAST_GROUP, index, e
+            ),
+        }
+    }
+    if joined > 0 {
+        info!(
+            "Listening for discovery on multicast group {} on {} interfaces",
+            DISCOVERY_MULTICAST_GROUP, joined
+        );
+    } else {
+        warn!(
+            "Could not join multicast group {}, IPv6 clients won't find the server",
+            DISCOVERY_MULTICAST_GROUP
+        );
+    }
+    Ok(socket.into())
+}
+
+/// An IPv4 address of the interface a hello from this IPv6 address came in on,
+/// or else of any interface but loopback.
+fn ipv4_address(from: &SocketAddrV6) -> Option<Ipv4Addr> {
+    let interfaces = match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            warn!("Could not list network interfaces: {}", e);
+            return None;
+        }
+    };
+    let in_subnet = |interface: &&if_addrs::Interface| match &interface.addr {
+        IfAddr::V6(v6) => {
+            let mask = v6.netmask.to_bits();
+            v6.ip.to_bits() & mask == from.ip().to_bits() & mask
+        }
+        IfAddr::V4(_) => false,
+    };
+    // link-local addresses are the same on every link, only the scope tells them apart.
+    let name = interfaces
+        .iter()
+        .find(|interface| from.scope_id() != 0 && interface.index == Some(from.scope_id()))
+        .or_else(|| interfaces.iter().find(in_subnet))
+        .map(|interface| interface.name.as_str());
+    let ipv4 = |interface: &if_addrs::Interface| match &interface.addr {
+        IfAddr::V4(v4) => Some(v4.ip),
+        IfAddr::V6(_) => None,
+    };
+    interfaces
+        .iter()
+        .filter(|interface| Some(interface.name.as_str()) == name)
+        .find_map(ipv4)
+        .or_else(|| {
+            interfaces
+                .iter()
+                .filter(|interface| !interface.is_loopback())
+                .find_map(ipv4)
+        })
+}
+
+/// The indices of all interfaces with an IPv6 address, or 0 to let the system pick
+/// one if they can't be listed.
+fn multicast_interfaces() -> BTreeSet<u32> {
+    match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces
+            .into_iter()
+            .filter(|interface| matches!(interface.addr, IfAddr::V6(_)) && !interface.is_loopback())
+            .filter_map(|interface| interface.index)
+            .collect(),
+        Err(e) => {
+            warn!("Could not list network interfaces: {}", e);
+            BTreeSet::from([0])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    };
+    use std::thread;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::state::InnerState;
+
+    fn hello(from: &UdpSocket, to: SocketAddr) -> ProtocolMessage {
+        let hello = ProtocolMessage::Hello {
+            protocol_version: None,
+        };
+        from.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        from.send_to(&rmp_serde::to_vec_named(&hello).unwrap(), to)
+            .unwrap();
+        let mut buf = [0u8; 1024];
+        let (size, _) = from.recv_from(&mut buf).unwrap();
+        rmp_serde::from_slice(&buf[..size]).unwrap()
+    }
+
+    #[test]
+    fn answers_ipv4_and_ipv6_hellos_on_separate_sockets() {
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let cancellation_token = Arc::new(AtomicBool::new(false));
+        let state = State::new(InnerState::new(
+            Arc::clone(&cancellation_token),
+            Config::default().streams,
+        ));
+        let mut listener =
+            UdpBroadcastListener::new("::".to_string(), port, "test".to_string(), 9022, state)
+                .unwrap();
+        assert_eq!(listener.sockets.len(), 2);
+        let thread = thread::spawn(move || listener.run());
+
+        let v4 = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let v6 = UdpSocket::bind("[::1]:0").unwrap();
+        // ENet is IPv4 only, IPv6 clients are told the IPv4 address of the interface.
+        for (socket, server, expected_address) in [
+            (v4, SocketAddr::from((Ipv4Addr::LOCALHOST, port)), None),
+            (
+                v6,
+                SocketAddr::from((Ipv6Addr::LOCALHOST, port)),
+                Some("127.0.0.1".to_string()),
+            ),
+        ] {
+            match hello(&socket, server) {
+                ProtocolMessage::Ack {
+                    name,
+                    port,
+                    address,
+                    ..
+                } => {
+                    assert_eq!(name, "test");
+                    assert_eq!(port, 9022);
+                    assert_eq!(address, expected_address);
                 }
+                m => panic!("Expected an ack, got {:?}", m),
             }
         }
+
+        cancellation_token.store(true, Ordering::Relaxed);
+        thread.join().unwrap();
     }
 }